@@ -0,0 +1,224 @@
+// Headless decoder, prints every decoded message as one JSON object per line.
+
+use dofus_decoder::{
+    game_servers::{capture_filter, DEFAULT_FILTER},
+    packet_capture::PacketCapture,
+    PacketDecoder, Proxy, SchemaRegistry,
+};
+use log::{info, warn};
+use pcap::{Activated, Capture, Device};
+use std::{
+    env,
+    error::Error,
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::ToSocketAddrs,
+    process,
+    sync::{atomic::AtomicBool, Arc, Mutex, PoisonError},
+};
+
+const USAGE: &str = "Usage: dofus-dump [OPTIONS]
+
+Options:
+  -i, --interface <NAME>  Capture on this interface (default: first available)
+  -r, --read <FILE>       Decode a .pcap/.pcapng file instead of sniffing
+  -f, --filter <BPF>      Capture filter, widened to the game servers
+                          the client is handed over to (default: \"tcp port 5555\")
+  -l, --listen <ADDR>     Relay connections made to this address instead of
+                          sniffing, the game has to be pointed there
+  -u, --upstream <HOST:PORT>
+                          Server the relayed connections go to
+  -o, --output <FILE>     Write JSON lines to a file instead of stdout
+  -p, --protocol <FILE>   messages.json to decode with (default: $DOFUS_MESSAGES_JSON
+                          or the embedded definition)
+  -P, --protocols <DIR>   Also load every <version>/messages.json of a directory,
+                          picked per connection from the handshake
+      --protocol-version <VERSION>
+                          Protocol version when it can't be detected
+  -h, --help              Print this help";
+
+struct Options {
+    interface: Option<String>,
+    file: Option<String>,
+    filter: String,
+    listen: Option<String>,
+    upstream: Option<String>,
+    output: Option<String>,
+    protocol: Option<String>,
+    protocols: Option<String>,
+    protocol_version: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        interface: None,
+        file: None,
+        filter: String::from(DEFAULT_FILTER),
+        listen: None,
+        upstream: None,
+        output: None,
+        protocol: None,
+        protocols: None,
+        protocol_version: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+
+        match arg.as_str() {
+            "-i" | "--interface" => options.interface = Some(value()?),
+            "-r" | "--read" => options.file = Some(value()?),
+            "-f" | "--filter" => options.filter = value()?,
+            "-l" | "--listen" => options.listen = Some(value()?),
+            "-u" | "--upstream" => options.upstream = Some(value()?),
+            "-o" | "--output" => options.output = Some(value()?),
+            "-p" | "--protocol" => options.protocol = Some(value()?),
+            "-P" | "--protocols" => options.protocols = Some(value()?),
+            "--protocol-version" => options.protocol_version = Some(value()?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+
+    if options.interface.is_some() && options.file.is_some() {
+        return Err(String::from("--interface and --read are exclusive"));
+    }
+    if options.listen.is_some() && (options.interface.is_some() || options.file.is_some()) {
+        return Err(String::from("--listen excludes --interface and --read"));
+    }
+    if options.listen.is_some() != options.upstream.is_some() {
+        return Err(String::from("--listen and --upstream go together"));
+    }
+
+    Ok(options)
+}
+
+fn main() {
+    env_logger::init();
+
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = run(&options) {
+        eprintln!("dofus-dump: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(options: &Options) -> Result<(), Box<dyn Error>> {
+    let mut schemas = match &options.protocol {
+        Some(path) => SchemaRegistry::from_file(path)?,
+        None => SchemaRegistry::load_default()?,
+    };
+    if let Some(dir) = &options.protocols {
+        schemas.load_dir(dir)?;
+    }
+    if let Some(version) = &options.protocol_version {
+        schemas.set_default_version(version)?;
+    }
+    let mut decoder = PacketDecoder::with_schemas(schemas);
+
+    let mut output: Box<dyn Write + Send> = match &options.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout()),
+    };
+
+    if let (Some(listen), Some(upstream)) = (&options.listen, &options.upstream) {
+        return relay(listen, upstream, decoder, output);
+    }
+
+    if let Some(path) = &options.file {
+        let mut cap = Capture::from_file(path)?;
+        cap.filter(&capture_filter(&options.filter), true)?;
+        return dump(&mut cap, &options.filter, &mut decoder, &mut output);
+    }
+
+    let device = match &options.interface {
+        Some(name) => Device::from(name.as_str()),
+        None => Device::lookup()?.ok_or("no device available")?,
+    };
+
+    let mut cap = Capture::from_device(device)?.immediate_mode(true).open()?;
+    cap.filter(&capture_filter(&options.filter), true)?;
+    dump(&mut cap, &options.filter, &mut decoder, &mut output)
+}
+
+fn dump<T: Activated + ?Sized>(
+    cap: &mut Capture<T>,
+    filter: &str,
+    decoder: &mut PacketDecoder,
+    output: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut packet_capture = PacketCapture::with_filter(filter);
+    packet_capture.set_linktype(cap.get_datalink());
+
+    loop {
+        let packet = match cap.next_packet() {
+            Ok(packet) => packet,
+            Err(pcap::Error::TimeoutExpired) => continue,
+            Err(pcap::Error::NoMorePackets) => break,
+            Err(err) => return Err(err.into()),
+        };
+
+        packet_capture.process(&packet, decoder);
+        if let Some(filter) = packet_capture.updated_filter() {
+            cap.filter(&filter, true)?;
+        }
+
+        for message in decoder.get_messages() {
+            serde_json::to_writer(&mut *output, &message)?;
+            writeln!(output)?;
+        }
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+fn relay(
+    listen: &str,
+    upstream: &str,
+    decoder: PacketDecoder,
+    output: Box<dyn Write + Send>,
+) -> Result<(), Box<dyn Error>> {
+    let upstream = upstream
+        .to_socket_addrs()?
+        .next()
+        .ok_or("upstream address not found")?;
+    let proxy = Proxy::bind(listen, upstream)?;
+    info!(
+        "Listening on {}, relaying to {}",
+        proxy.local_addr()?,
+        upstream
+    );
+
+    let output = Mutex::new(output);
+    proxy.run(
+        Arc::new(Mutex::new(decoder)),
+        Arc::new(move |messages| {
+            let mut output = output.lock().unwrap_or_else(PoisonError::into_inner);
+            let written = messages.iter().try_for_each(|message| {
+                serde_json::to_writer(&mut *output, message)?;
+                writeln!(output)
+            });
+            if let Err(err) = written.and_then(|_| output.flush()) {
+                warn!("Unable to write messages: {}", err);
+            }
+        }),
+        // relays until killed
+        &AtomicBool::new(false),
+    )?;
+
+    Ok(())
+}
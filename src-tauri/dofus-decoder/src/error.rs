@@ -0,0 +1,102 @@
+use std::{error::Error, fmt, io, path::PathBuf};
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The protocol definition could not be read.
+    Io(io::Error),
+    /// The protocol definition is not valid JSON.
+    Json(serde_json::Error),
+    /// The protocol definition does not have the expected shape.
+    InvalidSchema(String),
+    /// Loading the protocol definition from this file failed.
+    SchemaFile(PathBuf, Box<DecodeError>),
+    /// No message with this id in the protocol definition.
+    UnknownMessage(u16),
+    /// A message refers to a type missing from the protocol definition.
+    UnknownType(String),
+    /// No protocol definition registered for this version.
+    UnknownVersion(String),
+    /// A read went past the end of the message.
+    OutOfBounds {
+        type_name: &'static str,
+        offset: usize,
+        needed: usize,
+        available: usize,
+    },
+    /// The bytes read can't be converted to the expected type.
+    InvalidValue {
+        type_name: &'static str,
+        offset: usize,
+        reason: String,
+    },
+    /// Values nested deeper than the decoder follows, the message is malformed.
+    TooDeep { type_name: String, offset: usize },
+    /// A body to encode doesn't match the definition of its message.
+    InvalidBody { field: String, reason: String },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Io(err) => write!(f, "Unable to read the protocol definition: {}", err),
+            DecodeError::Json(err) => write!(f, "Malformed protocol definition: {}", err),
+            DecodeError::InvalidSchema(reason) => {
+                write!(f, "Invalid protocol definition: {}", reason)
+            }
+            DecodeError::SchemaFile(path, err) => write!(f, "{} ({})", err, path.display()),
+            DecodeError::UnknownMessage(id) => write!(f, "Unknown message id {}", id),
+            DecodeError::UnknownType(name) => write!(f, "Unknown type {}", name),
+            DecodeError::UnknownVersion(version) => {
+                write!(f, "Unknown protocol version {}", version)
+            }
+            DecodeError::OutOfBounds {
+                type_name,
+                offset,
+                needed,
+                available,
+            } => write!(
+                f,
+                "Unable to read {} at offset {}: {} bytes needed, {} available",
+                type_name, offset, needed, available
+            ),
+            DecodeError::InvalidValue {
+                type_name,
+                offset,
+                reason,
+            } => write!(f, "Invalid {} at offset {}: {}", type_name, offset, reason),
+            DecodeError::TooDeep { type_name, offset } => {
+                write!(
+                    f,
+                    "Unable to read {} at offset {}: nested too deep",
+                    type_name, offset
+                )
+            }
+            DecodeError::InvalidBody { field, reason } => {
+                write!(f, "Unable to encode {}: {}", field, reason)
+            }
+        }
+    }
+}
+
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DecodeError::Io(err) => Some(err),
+            DecodeError::Json(err) => Some(err),
+            DecodeError::SchemaFile(_, err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(err: io::Error) -> Self {
+        DecodeError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(err: serde_json::Error) -> Self {
+        DecodeError::Json(err)
+    }
+}
@@ -0,0 +1,376 @@
+use crate::{
+    packet_decoder::{DofusPacket, Source},
+    tcp_reassembly::FlowKey,
+};
+use log::{info, warn};
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, ToSocketAddrs},
+};
+
+/// Port of the login server, the only one known before the handoff.
+pub const LOGIN_PORT: u16 = 5555;
+
+/// Capture filter of the login server, to be widened by [`capture_filter`].
+pub const DEFAULT_FILTER: &str = "tcp port 5555";
+
+/// `filter` widened to the packets libpcap can't tell it matches.
+///
+/// Ports are only found in the first fragment of an IPv4 packet, and right
+/// after the fixed IPv6 header: later fragments and IPv6 packets with
+/// extension headers are all let through, the parser sorts them out. Frames
+/// with one or two VLAN tags are matched like untagged ones, which only works
+/// when `filter` doesn't use `vlan` itself.
+pub fn capture_filter(filter: &str) -> String {
+    let untagged = format!(
+        "({}) or (tcp and ip[6:2] & 0x1fff != 0) or (ip6 protochain 6 and not ip6 proto 6)",
+        filter
+    );
+    // each `vlan` moves the offsets of the rest of the filter past one more tag
+    format!("{0} or (vlan and ({0})) or (vlan and ({0}))", untagged)
+}
+
+/// Servers the capture follows: the login one, then the game servers it hands
+/// the client over to with `SelectedServerDataMessage`.
+#[derive(Debug, Clone)]
+pub struct GameServers {
+    base_filter: String,
+    endpoints: BTreeSet<(IpAddr, u16)>,
+}
+
+impl Default for GameServers {
+    fn default() -> GameServers {
+        GameServers::new(DEFAULT_FILTER)
+    }
+}
+
+impl GameServers {
+    /// `base_filter` is the capture filter before any server is learnt, as given by the user.
+    pub fn new(base_filter: &str) -> GameServers {
+        GameServers {
+            base_filter: base_filter.to_string(),
+            endpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn is_server(&self, addr: IpAddr, port: u16) -> bool {
+        port == LOGIN_PORT || self.endpoints.contains(&(addr, port))
+    }
+
+    /// Side that sent the bytes of `flow`.
+    pub fn source(&self, flow: &FlowKey) -> Source {
+        if self.is_server(flow.src_addr, flow.src_port) {
+            Source::Server
+        } else {
+            Source::Client
+        }
+    }
+
+    /// Remember the game servers announced in `messages`, returns whether any is new.
+    pub fn learn(&mut self, messages: &[DofusPacket]) -> bool {
+        let mut learnt = false;
+
+        for message in messages.iter() {
+            if message.name() != "SelectedServerDataMessage"
+                && message.name() != "SelectedServerDataExtendedMessage"
+            {
+                continue;
+            }
+
+            let body = message.body();
+            let address = body
+                .get("address")
+                .and_then(|address| address.as_str())
+                .unwrap_or_default();
+            let ports = body
+                .get("ports")
+                .and_then(|ports| ports.as_array())
+                .unwrap_or_default();
+
+            let addrs: Vec<IpAddr> = match address.parse() {
+                Ok(addr) => vec![addr],
+                // announced by host name, resolved the way the client will
+                Err(_) => match (address, 0).to_socket_addrs() {
+                    Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+                    Err(err) => {
+                        warn!("Unable to resolve game server {}: {}", address, err);
+                        continue;
+                    }
+                },
+            };
+
+            for port in ports.iter() {
+                // sent as VarShort, ports above 32767 come out negative
+                let port = match port.as_i64() {
+                    Some(port) => port as u16,
+                    None => continue,
+                };
+
+                for addr in addrs.iter() {
+                    if self.endpoints.insert((*addr, port)) {
+                        info!("Following game server {} ({}:{})", address, addr, port);
+                        learnt = true;
+                    }
+                }
+            }
+        }
+
+        learnt
+    }
+
+    /// Capture filter matching the base one and every server learnt, widened
+    /// by [`capture_filter`].
+    pub fn filter(&self) -> String {
+        let servers: Vec<String> = self
+            .endpoints
+            .iter()
+            .map(|(addr, port)| format!("(host {} and tcp port {})", addr, port))
+            .collect();
+
+        if servers.is_empty() {
+            capture_filter(&self.base_filter)
+        } else {
+            capture_filter(&format!(
+                "({}) or {}",
+                self.base_filter,
+                servers.join(" or ")
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_message, DecodedValue, PacketDecoder, Schema, Struct};
+    use pcap::{Capture, Linktype};
+    use std::{net::Ipv4Addr, time::Duration};
+
+    fn client(port: u16) -> FlowKey {
+        FlowKey {
+            src_addr: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            src_port: 40000,
+            dst_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)),
+            dst_port: port,
+        }
+    }
+
+    // `message` sending the client to `address`, as decoded from the login server
+    fn announce(message: &str, address: &str, ports: &[u16]) -> Vec<DofusPacket> {
+        let schema = Schema::embedded().unwrap();
+        let ports = ports
+            .iter()
+            .map(|port| DecodedValue::VarShort(*port as i16))
+            .collect();
+
+        let mut body = Struct::new(message);
+        body.push("serverId", 0..0, DecodedValue::VarUhShort(11));
+        body.push("address", 0..0, DecodedValue::Utf(address.to_string()));
+        body.push("ports", 0..0, DecodedValue::Array(ports));
+        body.push("canCreateNewCharacter", 0..0, DecodedValue::Boolean(true));
+        body.push("ticket", 0..0, DecodedValue::Array(vec![]));
+        if message == "SelectedServerDataExtendedMessage" {
+            body.push("servers", 0..0, DecodedValue::Array(vec![]));
+        }
+
+        let message = schema.message_by_name(message).unwrap();
+        let bytes = encode_message(&schema, message, &body, None).unwrap();
+        let mut decoder = PacketDecoder::new().unwrap();
+        let login = client(LOGIN_PORT).reverse();
+        decoder.decode_packet(login, &bytes, Source::Server, Duration::ZERO);
+        decoder.get_messages()
+    }
+
+    #[test]
+    fn learns_announced_servers() {
+        let mut servers = GameServers::default();
+        let game = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5));
+        assert_eq!(servers.source(&client(LOGIN_PORT)), Source::Client);
+        assert_eq!(
+            servers.source(&client(LOGIN_PORT).reverse()),
+            Source::Server
+        );
+
+        let selected = announce("SelectedServerDataMessage", "10.0.0.5", &[5556]);
+        assert!(servers.learn(&selected));
+        assert!(!servers.learn(&selected));
+        assert!(servers.is_server(game, 5556));
+        assert_eq!(servers.source(&client(5556).reverse()), Source::Server);
+        assert_eq!(servers.source(&client(5556)), Source::Client);
+
+        // ports above 32767 are sent negative
+        let extended = announce("SelectedServerDataExtendedMessage", "10.0.0.5", &[40443]);
+        assert!(servers.learn(&extended));
+        assert!(servers.is_server(game, 40443));
+        // only where they were announced
+        assert!(!servers.is_server(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 6)), 5556));
+
+        let hello = announce("SelectedServerDataMessage", "10.0.0.5", &[]);
+        assert!(!servers.learn(&hello));
+    }
+
+    #[test]
+    fn resolves_announced_host_names() {
+        let mut servers = GameServers::default();
+
+        assert!(servers.learn(&announce("SelectedServerDataMessage", "localhost", &[5556])));
+        assert!(servers.is_server(IpAddr::V4(Ipv4Addr::LOCALHOST), 5556));
+        assert!(!servers.is_server(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)), 5556));
+    }
+
+    #[test]
+    fn filters_learnt_servers() {
+        let mut servers = GameServers::new("tcp port 5555 and host 10.0.0.1");
+        assert_eq!(
+            servers.filter(),
+            capture_filter("tcp port 5555 and host 10.0.0.1")
+        );
+
+        servers.learn(&announce(
+            "SelectedServerDataMessage",
+            "10.0.0.5",
+            &[5556, 5557],
+        ));
+        assert_eq!(
+            servers.filter(),
+            capture_filter(
+                "(tcp port 5555 and host 10.0.0.1) or (host 10.0.0.5 and tcp port 5556) \
+                 or (host 10.0.0.5 and tcp port 5557)"
+            )
+        );
+    }
+
+    // Ethernet frame with the 802.1Q or 802.1ad `tags`, outermost first
+    fn ethernet(tags: &[u16], ethertype: u16, packet: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        for &tpid in tags {
+            frame.extend_from_slice(&tpid.to_be_bytes());
+            frame.extend_from_slice(&[0x00, 0x2a]);
+        }
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    // IPv4 packet from 10.0.0.1 to 10.0.0.2, `offset` in 8 bytes
+    fn ipv4_packet(protocol: u8, offset: u16, more: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0x00];
+        packet.extend_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+        let flags = offset | if more { 0x2000 } else { 0 };
+        packet.extend_from_slice(&[0x12, 0x34]);
+        packet.extend_from_slice(&flags.to_be_bytes());
+        packet.extend_from_slice(&[64, protocol, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn ipv4(protocol: u8, offset: u16, more: bool, payload: &[u8]) -> Vec<u8> {
+        ethernet(&[], 0x0800, &ipv4_packet(protocol, offset, more, payload))
+    }
+
+    // IPv6 packet from ::1 to ::2, `next` naming the first of the `extensions`
+    fn ipv6(next: u8, extensions: &[u8], segment: &[u8]) -> Vec<u8> {
+        let len = (extensions.len() + segment.len()) as u16;
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.extend_from_slice(&[next, 64]);
+        for last in [1, 2] {
+            packet.extend_from_slice(&[0; 15]);
+            packet.push(last);
+        }
+        packet.extend_from_slice(extensions);
+        packet.extend_from_slice(segment);
+        packet
+    }
+
+    // TCP header from `src` to `dst`, followed by `len` bytes of data
+    fn tcp(src: u16, dst: u16, len: usize) -> Vec<u8> {
+        let mut segment = src.to_be_bytes().to_vec();
+        segment.extend_from_slice(&dst.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        segment.resize(20 + len, 7);
+        segment
+    }
+
+    fn matches(filter: &str, frame: &[u8]) -> bool {
+        let capture = Capture::dead(Linktype::ETHERNET).unwrap();
+        capture.compile(filter, true).unwrap().filter(frame)
+    }
+
+    #[test]
+    fn lets_later_fragments_through() {
+        let mut servers = GameServers::default();
+        let login = servers.filter();
+        servers
+            .endpoints
+            .insert((IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 5556));
+        let game = servers.filter();
+
+        let first = ipv4(6, 0, true, &tcp(5555, 40000, 16));
+        let later = ipv4(6, 185, false, &[7; 64]);
+        let other = ipv4(6, 0, false, &tcp(443, 40000, 16));
+        let udp = ipv4(17, 185, false, &[7; 64]);
+
+        // libpcap alone drops the fragments without ports
+        assert!(matches(DEFAULT_FILTER, &first));
+        assert!(!matches(DEFAULT_FILTER, &later));
+
+        for filter in [login, game] {
+            assert!(matches(&filter, &first), "{}", filter);
+            assert!(matches(&filter, &later), "{}", filter);
+            assert!(!matches(&filter, &other), "{}", filter);
+            assert!(!matches(&filter, &udp), "{}", filter);
+        }
+        assert!(matches(
+            &servers.filter(),
+            &ipv4(6, 0, false, &tcp(40000, 5556, 0))
+        ));
+    }
+
+    #[test]
+    fn matches_below_vlan_tags() {
+        let login = ipv4_packet(6, 0, false, &tcp(5555, 40000, 16));
+        let other = ipv4_packet(6, 0, false, &tcp(443, 40000, 16));
+        let filter = GameServers::default().filter();
+
+        for tags in [&[][..], &[0x8100], &[0x88a8, 0x8100]] {
+            assert!(
+                matches(&filter, &ethernet(tags, 0x0800, &login)),
+                "{:?}",
+                tags
+            );
+            assert!(
+                !matches(&filter, &ethernet(tags, 0x0800, &other)),
+                "{:?}",
+                tags
+            );
+        }
+        // libpcap alone only looks at untagged frames
+        assert!(!matches(
+            DEFAULT_FILTER,
+            &ethernet(&[0x8100], 0x0800, &login)
+        ));
+    }
+
+    #[test]
+    fn matches_behind_ipv6_extension_headers() {
+        let login = tcp(5555, 40000, 16);
+        let other = tcp(443, 40000, 16);
+        // hop-by-hop options then a destination options header, both 8 bytes long
+        let extensions = [0x3c, 0, 1, 4, 0, 0, 0, 0, 6, 0, 1, 4, 0, 0, 0, 0];
+        let filter = GameServers::default().filter();
+
+        for tags in [&[][..], &[0x8100]] {
+            let plain = ethernet(tags, 0x86dd, &ipv6(6, &[], &login));
+            let extended = ethernet(tags, 0x86dd, &ipv6(0, &extensions, &login));
+            let plain_other = ethernet(tags, 0x86dd, &ipv6(6, &[], &other));
+
+            assert!(matches(&filter, &plain), "{:?}", tags);
+            assert!(matches(&filter, &extended), "{:?}", tags);
+            assert!(!matches(&filter, &plain_other), "{:?}", tags);
+        }
+        // libpcap alone only finds TCP right after the fixed header
+        let extended = ethernet(&[], 0x86dd, &ipv6(0, &extensions, &login));
+        assert!(!matches(DEFAULT_FILTER, &extended));
+    }
+}
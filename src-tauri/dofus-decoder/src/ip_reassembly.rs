@@ -0,0 +1,326 @@
+use log::{debug, warn};
+use pktparse::ipv4::IPv4Header;
+use std::{collections::HashMap, net::Ipv4Addr, time::Duration};
+
+// Fragments of a datagram still incomplete after this long are dropped, like hosts do.
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Bytes kept across every incomplete datagram, past that the oldest ones are dropped.
+const MAX_PENDING_BYTES: usize = 4 * 1024 * 1024;
+
+// Largest payload the 16 bits total length of an IPv4 header allows.
+const MAX_DATAGRAM_LEN: usize = 65535 - 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct DatagramKey {
+    src_addr: Ipv4Addr,
+    dst_addr: Ipv4Addr,
+    protocol: u8,
+    id: u16,
+}
+
+struct Datagram {
+    first_seen: Duration,
+    fragments: Vec<(usize, Vec<u8>)>,
+    // known once the last fragment came
+    len: Option<usize>,
+    bytes: usize,
+}
+
+impl Datagram {
+    fn new(first_seen: Duration) -> Datagram {
+        Datagram {
+            first_seen,
+            fragments: vec![],
+            len: None,
+            bytes: 0,
+        }
+    }
+
+    // the payload once the fragments cover it from start to end, overlaps taking the last copy
+    fn assemble(&mut self) -> Option<Vec<u8>> {
+        let len = self.len?;
+
+        self.fragments.sort_by_key(|(offset, _)| *offset);
+        let mut covered = 0;
+        for (offset, payload) in self.fragments.iter() {
+            if *offset > covered {
+                return None;
+            }
+            covered = covered.max(offset + payload.len());
+        }
+        if covered < len {
+            return None;
+        }
+
+        let mut datagram = vec![0; len];
+        for (offset, payload) in self.fragments.iter() {
+            let end = (offset + payload.len()).min(len);
+            if *offset < end {
+                datagram[*offset..end].copy_from_slice(&payload[..end - offset]);
+            }
+        }
+        Some(datagram)
+    }
+}
+
+/// Puts fragmented IPv4 datagrams back together.
+#[derive(Default)]
+pub struct FragmentReassembler {
+    datagrams: HashMap<DatagramKey, Datagram>,
+    pending_bytes: usize,
+}
+
+impl FragmentReassembler {
+    pub fn new() -> FragmentReassembler {
+        FragmentReassembler {
+            datagrams: HashMap::new(),
+            pending_bytes: 0,
+        }
+    }
+
+    /// Whether the packet of this header is only part of a datagram.
+    pub fn is_fragment(header: &IPv4Header) -> bool {
+        header.flags & 1 != 0 || header.fragment_offset != 0
+    }
+
+    /// Keep a fragment received at `time`, returns the payload of its datagram
+    /// once every fragment of it came. `protocol` is the raw value of the header one.
+    pub fn push(
+        &mut self,
+        header: &IPv4Header,
+        protocol: u8,
+        payload: &[u8],
+        time: Duration,
+    ) -> Option<Vec<u8>> {
+        self.expire(time);
+
+        // the offset counts 8 bytes units, the last fragment clears "more fragments"
+        let offset = usize::from(header.fragment_offset) * 8;
+        let last = header.flags & 1 == 0;
+        if offset + payload.len() > MAX_DATAGRAM_LEN {
+            warn!(
+                "IPv4 fragment at {} of {} bytes overflows its datagram",
+                offset,
+                payload.len()
+            );
+            return None;
+        }
+
+        let key = DatagramKey {
+            src_addr: header.source_addr,
+            dst_addr: header.dest_addr,
+            protocol,
+            id: header.id,
+        };
+        let datagram = self
+            .datagrams
+            .entry(key)
+            .or_insert_with(|| Datagram::new(time));
+
+        datagram.fragments.push((offset, payload.to_owned()));
+        datagram.bytes += payload.len();
+        self.pending_bytes += payload.len();
+        if last {
+            datagram.len = Some(offset + payload.len());
+        }
+
+        if let Some(assembled) = datagram.assemble() {
+            self.remove(&key);
+            return Some(assembled);
+        }
+
+        // too much is waiting, give up on the oldest datagrams
+        while self.pending_bytes > MAX_PENDING_BYTES {
+            let oldest = self
+                .datagrams
+                .iter()
+                .min_by_key(|(_, datagram)| datagram.first_seen)
+                .map(|(key, _)| *key);
+            match oldest {
+                Some(oldest) => {
+                    warn!(
+                        "Too many IPv4 fragments pending, dropping datagram {}",
+                        oldest.id
+                    );
+                    self.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+
+        None
+    }
+
+    fn expire(&mut self, time: Duration) {
+        let expired: Vec<DatagramKey> = self
+            .datagrams
+            .iter()
+            .filter(|(_, datagram)| time.saturating_sub(datagram.first_seen) > FRAGMENT_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in expired.iter() {
+            debug!(
+                "IPv4 datagram {} timed out before its last fragment",
+                key.id
+            );
+            self.remove(key);
+        }
+    }
+
+    fn remove(&mut self, key: &DatagramKey) {
+        if let Some(datagram) = self.datagrams.remove(key) {
+            self.pending_bytes -= datagram.bytes;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pktparse::ip::IPProtocol;
+
+    const TCP: u8 = 6;
+    const UDP: u8 = 17;
+
+    fn header(id: u16, offset: usize, more: bool) -> IPv4Header {
+        IPv4Header {
+            version: 4,
+            ihl: 20,
+            tos: 0,
+            length: 0,
+            id,
+            flags: u8::from(more),
+            fragment_offset: (offset / 8) as u16,
+            ttl: 64,
+            protocol: IPProtocol::TCP,
+            chksum: 0,
+            source_addr: Ipv4Addr::new(10, 0, 0, 1),
+            dest_addr: Ipv4Addr::new(10, 0, 0, 2),
+        }
+    }
+
+    fn datagram(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    // the fragment of `data` between `start` and `end`, the last one if it ends it
+    fn push(
+        reassembler: &mut FragmentReassembler,
+        id: u16,
+        data: &[u8],
+        (start, end): (usize, usize),
+        millis: u64,
+    ) -> Option<Vec<u8>> {
+        let header = header(id, start, end < data.len());
+        let time = Duration::from_millis(millis);
+        reassembler.push(&header, TCP, &data[start..end], time)
+    }
+
+    #[test]
+    fn tells_fragments() {
+        assert!(!FragmentReassembler::is_fragment(&header(1, 0, false)));
+        assert!(FragmentReassembler::is_fragment(&header(1, 0, true)));
+        assert!(FragmentReassembler::is_fragment(&header(1, 64, false)));
+    }
+
+    #[test]
+    fn reassembles_in_order() {
+        let data = datagram(40);
+        let mut reassembler = FragmentReassembler::new();
+
+        assert_eq!(push(&mut reassembler, 1, &data, (0, 16), 0), None);
+        assert_eq!(push(&mut reassembler, 1, &data, (16, 32), 0), None);
+        assert_eq!(push(&mut reassembler, 1, &data, (32, 40), 0), Some(data));
+        assert!(reassembler.datagrams.is_empty());
+        assert_eq!(reassembler.pending_bytes, 0);
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let data = datagram(40);
+        let mut reassembler = FragmentReassembler::new();
+
+        assert_eq!(push(&mut reassembler, 1, &data, (32, 40), 0), None);
+        assert_eq!(push(&mut reassembler, 1, &data, (0, 16), 0), None);
+        assert_eq!(push(&mut reassembler, 1, &data, (16, 32), 0), Some(data));
+    }
+
+    #[test]
+    fn reassembles_overlapping_fragments() {
+        let data = datagram(40);
+        let mut reassembler = FragmentReassembler::new();
+
+        assert_eq!(push(&mut reassembler, 1, &data, (16, 40), 0), None);
+        assert_eq!(push(&mut reassembler, 1, &data, (0, 8), 0), None);
+        assert_eq!(push(&mut reassembler, 1, &data, (0, 24), 0), Some(data));
+    }
+
+    #[test]
+    fn keeps_datagrams_apart() {
+        let data = datagram(40);
+        let mut reassembler = FragmentReassembler::new();
+
+        assert_eq!(push(&mut reassembler, 1, &data, (0, 16), 0), None);
+        assert_eq!(push(&mut reassembler, 2, &data, (16, 40), 0), None);
+
+        // same addresses and id, another protocol
+        let last = header(1, 16, false);
+        let time = Duration::ZERO;
+        assert_eq!(reassembler.push(&last, UDP, &data[16..], time), None);
+        assert_eq!(reassembler.datagrams.len(), 3);
+
+        assert_eq!(push(&mut reassembler, 1, &data, (16, 40), 0), Some(data));
+    }
+
+    #[test]
+    fn drops_timed_out_fragments() {
+        let data = datagram(40);
+        let mut reassembler = FragmentReassembler::new();
+
+        assert_eq!(push(&mut reassembler, 1, &data, (0, 16), 0), None);
+        assert_eq!(push(&mut reassembler, 1, &data, (16, 40), 31_000), None);
+        assert_eq!(reassembler.datagrams.len(), 1);
+        assert_eq!(reassembler.pending_bytes, 24);
+
+        assert_eq!(
+            push(&mut reassembler, 1, &data, (0, 16), 40_000),
+            Some(data)
+        );
+    }
+
+    #[test]
+    fn bounds_pending_bytes() {
+        let data = datagram(60_000);
+        let mut reassembler = FragmentReassembler::new();
+
+        // only the first halves come
+        let count = 2 * MAX_PENDING_BYTES / 30_000;
+        for id in 0..count as u16 {
+            let time = u64::from(id);
+            assert_eq!(push(&mut reassembler, id, &data, (0, 30_000), time), None);
+            assert!(reassembler.pending_bytes <= MAX_PENDING_BYTES);
+        }
+
+        // the oldest ones went first
+        let time = count as u64;
+        assert_eq!(
+            push(&mut reassembler, 0, &data, (30_000, 60_000), time),
+            None
+        );
+        let last = count as u16 - 1;
+        let end = push(&mut reassembler, last, &data, (30_000, 60_000), time);
+        assert_eq!(end, Some(data));
+    }
+
+    #[test]
+    fn rejects_oversized_datagrams() {
+        let data = datagram(MAX_DATAGRAM_LEN + 8);
+        let mut reassembler = FragmentReassembler::new();
+
+        let end = data.len();
+        assert_eq!(push(&mut reassembler, 1, &data, (end - 16, end), 0), None);
+        assert!(reassembler.datagrams.is_empty());
+    }
+}
@@ -0,0 +1,18 @@
+pub mod error;
+pub mod game_servers;
+pub mod ip_reassembly;
+pub mod packet_capture;
+pub mod packet_decoder;
+pub mod packet_encoder;
+pub mod packet_parse;
+pub mod proxy;
+pub mod schema;
+pub mod tcp_reassembly;
+pub mod value;
+
+pub use error::DecodeError;
+pub use packet_decoder::{decode_body, DofusPacket, MessageHeader, PacketDecoder, Source};
+pub use packet_encoder::encode_message;
+pub use proxy::Proxy;
+pub use schema::{Schema, SchemaRegistry};
+pub use value::{DecodedValue, Struct};
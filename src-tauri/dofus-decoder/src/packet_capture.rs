@@ -0,0 +1,189 @@
+use crate::game_servers::{GameServers, DEFAULT_FILTER};
+use crate::packet_decoder::PacketDecoder;
+use crate::packet_parse::{PacketHeader, PacketParse, ParsedPacket};
+use crate::tcp_reassembly::{FlowKey, StreamData, TcpReassembler};
+use log::{debug, warn};
+use pcap::{Linktype, Packet};
+use std::{
+    net::IpAddr,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Default)]
+pub struct PacketCapture {
+    parser: PacketParse,
+    reassembler: TcpReassembler,
+    servers: GameServers,
+    filter_changed: bool,
+}
+
+impl PacketCapture {
+    pub fn new() -> PacketCapture {
+        PacketCapture::with_filter(DEFAULT_FILTER)
+    }
+
+    /// `filter` is the one the capture was opened with, widened as game servers are learnt.
+    pub fn with_filter(filter: &str) -> PacketCapture {
+        PacketCapture {
+            parser: PacketParse::new(),
+            reassembler: TcpReassembler::new(),
+            servers: GameServers::new(filter),
+            filter_changed: false,
+        }
+    }
+
+    /// Datalink of the capture the frames come from, Ethernet unless told otherwise.
+    pub fn set_linktype(&mut self, linktype: Linktype) {
+        if !PacketParse::supports(linktype) {
+            warn!(
+                "Unsupported link type {}, nothing will be decoded",
+                linktype
+                    .get_name()
+                    .unwrap_or_else(|_| linktype.0.to_string())
+            );
+        }
+        self.parser = PacketParse::with_linktype(linktype);
+    }
+
+    pub fn servers(&self) -> &GameServers {
+        &self.servers
+    }
+
+    /// The filter to apply once the client was handed over to a new game server.
+    pub fn updated_filter(&mut self) -> Option<String> {
+        if self.filter_changed {
+            self.filter_changed = false;
+            Some(self.servers.filter())
+        } else {
+            None
+        }
+    }
+
+    /// Parse a captured frame and feed its TCP payload to the decoder.
+    pub fn process(&mut self, packet: &Packet, decoder: &mut PacketDecoder) {
+        let parsed = self.get_packet(packet);
+
+        // only hand ordered and deduplicated bytes to the decoder
+        if let Some(stream) = self.reassemble(&parsed, capture_time(packet)) {
+            if stream.gap {
+                decoder.reset(&stream.flow);
+            }
+
+            let source = self.servers.source(&stream.flow);
+            let pending = decoder.pending_messages().len();
+            decoder.decode_packet(stream.flow, &stream.payload, source, capture_time(packet));
+
+            if self.servers.learn(&decoder.pending_messages()[pending..]) {
+                self.filter_changed = true;
+            }
+        }
+
+        // the decoder keeps a connection until none of its directions is open
+        for flow in self.reassembler.closed_flows() {
+            if self.reassembler.is_open(&flow.reverse()) {
+                decoder.reset(&flow);
+            } else {
+                decoder.close(&flow);
+            }
+        }
+    }
+
+    pub fn get_packet(&mut self, packet: &Packet) -> ParsedPacket {
+        let data = packet.data.to_owned();
+        let len = packet.header.len;
+
+        let parsed_packet: ParsedPacket =
+            match self.parser.parse_packet(data, len, capture_time(packet)) {
+                Err(e) => {
+                    debug!("Skipping frame: {}", e);
+                    ParsedPacket::new()
+                }
+                Ok(parsed) => parsed,
+            };
+
+        parsed_packet
+    }
+
+    pub fn get_packet_meta(
+        &self,
+        parsed_packet: &ParsedPacket,
+    ) -> (String, String, String, String) {
+        let mut src_addr = "".to_string();
+        let mut dst_addr = "".to_string();
+        let mut src_port = "".to_string();
+        let mut dst_port = "".to_string();
+
+        parsed_packet.headers.iter().for_each(|pack| {
+            match pack {
+                PacketHeader::Tcp(packet) => {
+                    src_port = packet.source_port.to_string();
+                    dst_port = packet.dest_port.to_string();
+                }
+                PacketHeader::Udp(packet) => {
+                    src_port = packet.source_port.to_string();
+                    dst_port = packet.dest_port.to_string();
+                }
+                PacketHeader::Ipv4(packet) => {
+                    src_addr = IpAddr::V4(packet.source_addr).to_string();
+                    dst_addr = IpAddr::V4(packet.dest_addr).to_string();
+                }
+                PacketHeader::Ipv6(packet) => {
+                    src_addr = IpAddr::V6(packet.source_addr).to_string();
+                    dst_addr = IpAddr::V6(packet.dest_addr).to_string();
+                }
+                PacketHeader::Arp(packet) => {
+                    src_addr = packet.src_addr.to_string();
+                    dst_addr = packet.dest_addr.to_string();
+                }
+                _ => {}
+            };
+        });
+
+        (src_addr, src_port, dst_addr, dst_port)
+    }
+
+    pub fn reassemble(
+        &mut self,
+        parsed_packet: &ParsedPacket,
+        time: Duration,
+    ) -> Option<StreamData> {
+        let (flow, tcp_header) = FlowKey::from_headers(&parsed_packet.headers)?;
+        self.reassembler
+            .push(flow, tcp_header, &parsed_packet.payload, time)
+    }
+}
+
+/// Delays packets read from a capture file so they come out
+/// with the same spacing they were recorded with.
+#[derive(Default)]
+pub struct ReplayClock {
+    origin: Option<(Duration, Instant)>,
+}
+
+impl ReplayClock {
+    pub fn new() -> ReplayClock {
+        ReplayClock { origin: None }
+    }
+
+    pub fn wait(&mut self, packet: &Packet) {
+        let ts = capture_time(packet);
+
+        match self.origin {
+            None => self.origin = Some((ts, Instant::now())),
+            Some((first_ts, started)) => {
+                let due = started + ts.saturating_sub(first_ts);
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                }
+            }
+        }
+    }
+}
+
+/// Capture timestamp of a frame, since the epoch.
+pub fn capture_time(packet: &Packet) -> Duration {
+    Duration::from_secs(packet.header.ts.tv_sec as u64)
+        + Duration::from_micros(packet.header.ts.tv_usec as u64)
+}
@@ -0,0 +1,1115 @@
+use crate::{
+    error::DecodeError,
+    schema::{FieldDef, FieldKind, Length, PrimitiveType, Schema, SchemaRegistry, TypeDef},
+    tcp_reassembly::FlowKey,
+    value::{DecodedValue, FieldSpan, Struct},
+};
+use bytebuffer::ByteBuffer;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::{collections::HashMap, convert::TryInto, sync::Arc, time::Duration};
+
+// Nesting followed inside a message, real ones stay far below. Polymorphic
+// fields can hold their own type, past this it's garbage or an attack on the stack.
+const MAX_DEPTH: usize = 64;
+
+pub(crate) trait Buffer {
+    fn bytes_available(&self) -> usize;
+    fn check(&self, type_name: &'static str, needed: usize) -> Result<(), DecodeError>;
+    fn swap_bytes(&mut self, _bytes: &mut ByteBuffer, _len: usize);
+    fn read(&mut self, primitive: PrimitiveType) -> Result<DecodedValue, DecodeError>;
+    fn read_byte(&mut self) -> Result<i8, DecodeError>;
+    fn read_unsigned_byte(&mut self) -> Result<u8, DecodeError>;
+    fn read_short(&mut self) -> Result<i16, DecodeError>;
+    fn read_unsigned_short(&mut self) -> Result<u16, DecodeError>;
+    fn read_int(&mut self) -> Result<i32, DecodeError>;
+    fn read_unsigned_int(&mut self) -> Result<u32, DecodeError>;
+    fn read_float(&mut self) -> Result<f32, DecodeError>;
+    fn read_double(&mut self) -> Result<f64, DecodeError>;
+    fn read_utf(&mut self) -> Result<String, DecodeError>;
+    fn read_var_int(&mut self) -> Result<i32, DecodeError>;
+    fn read_var_uh_int(&mut self) -> Result<u32, DecodeError>;
+    fn read_var_short(&mut self) -> Result<i16, DecodeError>;
+    fn read_var_uh_short(&mut self) -> Result<u16, DecodeError>;
+    fn read_var_uh_long(&mut self) -> Result<u64, DecodeError>;
+    fn read_var_long(&mut self) -> Result<i64, DecodeError>;
+    fn read_var_bits(&mut self, type_name: &'static str, bits: u32) -> Result<u64, DecodeError>;
+}
+
+impl Buffer for ByteBuffer {
+    fn bytes_available(&self) -> usize {
+        self.len() - self.get_rpos()
+    }
+    fn check(&self, type_name: &'static str, needed: usize) -> Result<(), DecodeError> {
+        if needed > self.bytes_available() {
+            return Err(DecodeError::OutOfBounds {
+                type_name,
+                offset: self.get_rpos(),
+                needed,
+                available: self.bytes_available(),
+            });
+        }
+        Ok(())
+    }
+    fn swap_bytes(&mut self, bytes: &mut ByteBuffer, len: usize) {
+        let mut length = len;
+        if length == 0 {
+            length = self.bytes_available();
+        }
+
+        if length > self.bytes_available() {
+            debug!("End of buffer was encountered.");
+            return;
+        }
+
+        bytes.write_bytes(&self.read_bytes(length));
+    }
+    fn read_byte(&mut self) -> Result<i8, DecodeError> {
+        self.check("Byte", 1)?;
+        Ok(self.read_i8())
+    }
+    fn read_unsigned_byte(&mut self) -> Result<u8, DecodeError> {
+        self.check("UnsignedByte", 1)?;
+        Ok(self.read_u8())
+    }
+    fn read_short(&mut self) -> Result<i16, DecodeError> {
+        self.check("Short", 2)?;
+        Ok(self.read_i16())
+    }
+    fn read_unsigned_short(&mut self) -> Result<u16, DecodeError> {
+        self.check("UnsignedShort", 2)?;
+        Ok(self.read_u16())
+    }
+    fn read_int(&mut self) -> Result<i32, DecodeError> {
+        self.check("Int", 4)?;
+        Ok(self.read_i32())
+    }
+    fn read_unsigned_int(&mut self) -> Result<u32, DecodeError> {
+        self.check("UnsignedInt", 4)?;
+        Ok(self.read_u32())
+    }
+    fn read_float(&mut self) -> Result<f32, DecodeError> {
+        self.check("Float", 4)?;
+        Ok(self.read_f32())
+    }
+    fn read_double(&mut self) -> Result<f64, DecodeError> {
+        self.check("Double", 8)?;
+        Ok(self.read_f64())
+    }
+    fn read_utf(&mut self) -> Result<String, DecodeError> {
+        let offset = self.get_rpos();
+        self.check("UTF", 2)?;
+        let n_of_bytes = self.read_u16() as usize;
+        self.check("UTF", n_of_bytes)?;
+        let v = self.read_bytes(n_of_bytes);
+
+        String::from_utf8(v).map_err(|err| DecodeError::InvalidValue {
+            type_name: "UTF",
+            offset,
+            reason: err.to_string(),
+        })
+    }
+    // signed values are sent as the bits of their two's complement
+    fn read_var_int(&mut self) -> Result<i32, DecodeError> {
+        Ok(self.read_var_bits("VarInt", 32)? as u32 as i32)
+    }
+    fn read_var_uh_int(&mut self) -> Result<u32, DecodeError> {
+        Ok(self.read_var_bits("VarUhInt", 32)? as u32)
+    }
+    fn read_var_short(&mut self) -> Result<i16, DecodeError> {
+        Ok(self.read_var_bits("VarShort", 16)? as u16 as i16)
+    }
+    fn read_var_uh_short(&mut self) -> Result<u16, DecodeError> {
+        Ok(self.read_var_bits("VarUhShort", 16)? as u16)
+    }
+    fn read_var_uh_long(&mut self) -> Result<u64, DecodeError> {
+        self.read_var_bits("VarUhLong", 64)
+    }
+    fn read_var_long(&mut self) -> Result<i64, DecodeError> {
+        Ok(self.read_var_bits("VarLong", 64)? as i64)
+    }
+    // base 128 digits, least significant first, the high bit telling another byte follows
+    fn read_var_bits(&mut self, type_name: &'static str, bits: u32) -> Result<u64, DecodeError> {
+        let start = self.get_rpos();
+        let mut value: u64 = 0;
+        let mut offset = 0;
+        while offset < bits {
+            self.check(type_name, 1)?;
+            let b = self.read_u8();
+            let chunk = u64::from(b & 127);
+            if bits - offset < 7 && chunk >> (bits - offset) != 0 {
+                return Err(DecodeError::InvalidValue {
+                    type_name,
+                    offset: start,
+                    reason: format!("does not fit in {} bits", bits),
+                });
+            }
+            value |= chunk << offset;
+            if b & 128 == 0 {
+                return Ok(value);
+            }
+            offset += 7;
+        }
+        Err(DecodeError::InvalidValue {
+            type_name,
+            offset: start,
+            reason: String::from("too much data"),
+        })
+    }
+    fn read(&mut self, primitive: PrimitiveType) -> Result<DecodedValue, DecodeError> {
+        Ok(match primitive {
+            PrimitiveType::UnsignedByte => DecodedValue::UnsignedByte(self.read_unsigned_byte()?),
+            PrimitiveType::Byte => DecodedValue::Byte(self.read_byte()?),
+            PrimitiveType::UnsignedShort => {
+                DecodedValue::UnsignedShort(self.read_unsigned_short()?)
+            }
+            PrimitiveType::Short => DecodedValue::Short(self.read_short()?),
+            PrimitiveType::Int => DecodedValue::Int(self.read_int()?),
+            PrimitiveType::UnsignedInt => DecodedValue::UnsignedInt(self.read_unsigned_int()?),
+            PrimitiveType::Float => DecodedValue::Float(self.read_float()?),
+            PrimitiveType::Boolean => DecodedValue::Boolean(self.read_byte()? != 0),
+            PrimitiveType::Utf => DecodedValue::Utf(self.read_utf()?),
+            PrimitiveType::Double => DecodedValue::Double(self.read_double()?),
+            PrimitiveType::VarUhLong => DecodedValue::VarUhLong(self.read_var_uh_long()?),
+            PrimitiveType::VarLong => DecodedValue::VarLong(self.read_var_long()?),
+            PrimitiveType::VarUhInt => DecodedValue::VarUhInt(self.read_var_uh_int()?),
+            PrimitiveType::VarInt => DecodedValue::VarInt(self.read_var_int()?),
+            PrimitiveType::VarUhShort => DecodedValue::VarUhShort(self.read_var_uh_short()?),
+            PrimitiveType::VarShort => DecodedValue::VarShort(self.read_var_short()?),
+            PrimitiveType::ByteArray => DecodedValue::ByteArray({
+                let content_len = self.read_var_uh_int()? as usize;
+                self.check("ByteArray", content_len)?;
+                self.read_bytes(content_len)
+            }),
+        })
+    }
+}
+
+/// Side of the connection that sent a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Source {
+    Client,
+    Server,
+}
+
+/// Header preceding every message.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MessageHeader {
+    pub id: u16,
+    /// Number of bytes of the length, between 0 and 3.
+    pub length_type: u8,
+    pub length: usize,
+    /// Sent by the client only.
+    pub instance_id: Option<u32>,
+    /// The header bytes as hex.
+    pub raw: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DofusPacket {
+    source: Source,
+    /// Capture time of the first and last segments of the message,
+    /// in seconds since the epoch with microseconds.
+    time: String,
+    end_time: String,
+    id: u16,
+    name: String,
+    header: MessageHeader,
+    /// The message bytes after the header as hex, offsets of `layout` are relative to it.
+    raw: String,
+    body: Struct,
+    layout: Vec<FieldSpan>,
+}
+
+impl DofusPacket {
+    fn new(source: Source, header: MessageHeader, raw: String) -> DofusPacket {
+        DofusPacket {
+            source,
+            time: String::new(),
+            end_time: String::new(),
+            id: header.id,
+            name: "".to_string(),
+            header,
+            raw,
+            body: Struct::default(),
+            layout: Vec::new(),
+        }
+    }
+
+    pub fn source(&self) -> Source {
+        self.source
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn set_time(&mut self, first: Duration, last: Duration) {
+        self.time = format_time(first);
+        self.end_time = format_time(last);
+    }
+
+    pub fn header(&self) -> &MessageHeader {
+        &self.header
+    }
+
+    pub fn body(&self) -> &Struct {
+        &self.body
+    }
+}
+
+// Partially received message of one direction of one connection
+struct StreamState {
+    sba: ByteBuffer,
+    split_packet: bool,
+    split_packet_header: MessageHeader,
+    split_packet_source: Source,
+    split_packet_time: Duration,
+    // start of a header cut by the end of a segment, `split_packet_time` is when it came
+    header_bytes: Vec<u8>,
+}
+
+impl StreamState {
+    fn new() -> StreamState {
+        StreamState {
+            sba: ByteBuffer::new(),
+            split_packet: false,
+            split_packet_header: MessageHeader::default(),
+            split_packet_source: Source::Server,
+            split_packet_time: Duration::ZERO,
+            header_bytes: vec![],
+        }
+    }
+
+    fn keep_header(&mut self, header_bytes: &[u8], time: Duration) {
+        self.header_bytes = header_bytes.to_owned();
+        self.split_packet_time = time;
+    }
+
+    fn is_pending(&self) -> bool {
+        self.split_packet || !self.header_bytes.is_empty()
+    }
+}
+
+pub struct PacketDecoder {
+    streams: HashMap<FlowKey, StreamState>,
+    queue: Vec<DofusPacket>,
+    schemas: SchemaRegistry,
+    // detected protocol version of each connection, `None` follows the default one
+    connections: HashMap<FlowKey, Option<String>>,
+}
+
+impl PacketDecoder {
+    pub fn new() -> Result<PacketDecoder, DecodeError> {
+        Ok(PacketDecoder::with_schemas(SchemaRegistry::load_default()?))
+    }
+
+    pub fn with_schemas(schemas: SchemaRegistry) -> PacketDecoder {
+        PacketDecoder {
+            streams: HashMap::new(),
+            queue: Vec::<DofusPacket>::new(),
+            schemas,
+            connections: HashMap::new(),
+        }
+    }
+
+    pub fn schemas(&self) -> &SchemaRegistry {
+        &self.schemas
+    }
+
+    pub fn schemas_mut(&mut self) -> &mut SchemaRegistry {
+        &mut self.schemas
+    }
+
+    /// Decode the next bytes of a flow sent by `source`, `time` being when they were captured.
+    pub fn decode_packet(
+        &mut self,
+        flow: FlowKey,
+        tcp_content: &[u8],
+        source: Source,
+        time: Duration,
+    ) {
+        let mut stream = self.streams.remove(&flow).unwrap_or_else(StreamState::new);
+
+        let schema = self.connection_schema(&flow, tcp_content, source);
+        self.decode_stream(&mut stream, &schema, tcp_content, source, time);
+
+        // only keep the state while a message is still incomplete
+        if stream.is_pending() {
+            self.streams.insert(flow, stream);
+        }
+    }
+
+    // the first message of a connection tells which protocol version it speaks
+    fn connection_schema(
+        &mut self,
+        flow: &FlowKey,
+        tcp_content: &[u8],
+        source: Source,
+    ) -> Arc<Schema> {
+        let schemas = &self.schemas;
+        self.connections
+            .entry(flow.connection())
+            .or_insert_with(|| {
+                let version = schemas.detect(tcp_content, source == Source::Client);
+                match &version {
+                    Some(version) => info!("Connection speaks protocol {}", version),
+                    None => info!(
+                        "Unable to detect the protocol version, using {}",
+                        schemas.default_version()
+                    ),
+                }
+                version
+            });
+
+        self.schema(flow)
+    }
+
+    /// Protocol definition the connection of `flow` is decoded with.
+    pub fn schema(&self, flow: &FlowKey) -> Arc<Schema> {
+        self.connections
+            .get(&flow.connection())
+            .and_then(|version| version.as_ref())
+            .and_then(|version| self.schemas.get(version))
+            .unwrap_or_else(|| self.schemas.default_schema())
+    }
+
+    fn decode_stream(
+        &mut self,
+        stream: &mut StreamState,
+        schema: &Schema,
+        tcp_content: &[u8],
+        source: Source,
+        time: Duration,
+    ) {
+        // finish the header the previous segment ended in
+        let joined;
+        let mut header_time = time;
+        let tcp_content = if stream.header_bytes.is_empty() {
+            tcp_content
+        } else {
+            header_time = stream.split_packet_time;
+            joined = [&stream.header_bytes, tcp_content].concat();
+            stream.header_bytes.clear();
+            &joined[..]
+        };
+
+        let mut ba = ByteBuffer::from_bytes(tcp_content);
+        while ba.bytes_available() > 0 {
+            if stream.split_packet {
+                let split_packet_length = stream.split_packet_header.length;
+                info!(
+                    "We're split, at {} + {}, looking for {}",
+                    stream.sba.len(),
+                    ba.bytes_available(),
+                    split_packet_length
+                );
+
+                if stream.sba.len() + ba.bytes_available() < split_packet_length {
+                    ba.swap_bytes(&mut stream.sba, ba.bytes_available());
+                } else {
+                    let offset = stream.sba.len();
+                    ba.swap_bytes(&mut stream.sba, split_packet_length - offset);
+
+                    self.decode_message(
+                        &stream.split_packet_header,
+                        &stream.sba.to_bytes(),
+                        stream.split_packet_source,
+                        schema,
+                        stream.split_packet_time,
+                        time,
+                    );
+
+                    // reset
+                    *stream = StreamState::new();
+                }
+            } else {
+                let header_start = ba.get_rpos();
+                let hi_header_len = if source == Source::Client { 6 } else { 2 };
+                if ba.bytes_available() < hi_header_len {
+                    stream.keep_header(&tcp_content[header_start..], header_time);
+                    return;
+                }
+
+                let hi_header = ba.read_u16();
+                let packet_id = hi_header >> 2;
+                let length_type = hi_header & 3;
+
+                let mut length: usize = 0;
+                let mut instance_id = None;
+
+                if source == Source::Client {
+                    instance_id = Some(ba.read_u32());
+                }
+
+                if schema.message(packet_id).is_none() {
+                    info!(
+                        "Might be ETH trailer -> unknown id: {} - bytes available {}",
+                        packet_id,
+                        ba.bytes_available()
+                    );
+
+                    break;
+                }
+
+                if ba.bytes_available() < length_type as usize {
+                    stream.keep_header(&tcp_content[header_start..], header_time);
+                    return;
+                }
+
+                if length_type == 0 {
+                    length = 0;
+                } else if length_type == 1 {
+                    length = ba.read_u8() as usize;
+                } else if length_type == 2 {
+                    length = ba.read_u16() as usize;
+                } else if length_type == 3 {
+                    length = ((ba.read_i8() as usize & 255) << 16)
+                        + ((ba.read_i8() as usize & 255) << 8)
+                        + (ba.read_i8() as usize & 255);
+                }
+
+                info!(
+                    "length {} | available {} | packetId {}",
+                    length,
+                    ba.bytes_available(),
+                    packet_id,
+                );
+
+                let header = MessageHeader {
+                    id: packet_id,
+                    length_type: length_type as u8,
+                    length,
+                    instance_id,
+                    raw: to_hex(&tcp_content[header_start..ba.get_rpos()]),
+                };
+
+                if length > ba.bytes_available() {
+                    stream.split_packet = true;
+                    stream.split_packet_source = source;
+                    stream.split_packet_time = header_time;
+                    stream.split_packet_header = header;
+
+                    ba.swap_bytes(&mut stream.sba, ba.bytes_available());
+                } else {
+                    let payload = ba.read_bytes(length);
+                    self.decode_message(&header, &payload, source, schema, header_time, time);
+
+                    info!(
+                        "Ended to decode the packet, ba left: {}",
+                        ba.bytes_available()
+                    );
+                }
+                header_time = time;
+            }
+        }
+    }
+
+    fn decode_message(
+        &mut self,
+        header: &MessageHeader,
+        payload: &[u8],
+        source: Source,
+        schema: &Schema,
+        first: Duration,
+        last: Duration,
+    ) {
+        let mut packet_content = ByteBuffer::from_bytes(payload);
+
+        match PacketDecoder::parse_ba_to_object(&mut packet_content, header, source, schema) {
+            Ok(mut obj) => {
+                obj.set_time(first, last);
+                self.queue.push(obj)
+            }
+            Err(err) => error!("{}", err),
+        }
+
+        if packet_content.bytes_available() != 0 {
+            warn!("warning: forced to trim a packet !");
+        }
+    }
+
+    fn parse_ba_to_object(
+        packet_content: &mut ByteBuffer,
+        header: &MessageHeader,
+        source: Source,
+        schema: &Schema,
+    ) -> Result<DofusPacket, DecodeError> {
+        let mut dofus_packet =
+            DofusPacket::new(source, header.clone(), to_hex(&packet_content.to_bytes()));
+
+        let message = schema
+            .message(header.id)
+            .ok_or(DecodeError::UnknownMessage(header.id))?;
+
+        dofus_packet.name = message.name.clone();
+
+        dofus_packet.body = PacketDecoder::deserialize(packet_content, message, schema, 0)?;
+        dofus_packet.layout = dofus_packet.body.layout();
+
+        Ok(dofus_packet)
+    }
+
+    // `depth` is the number of values `type_def` is nested in
+    fn deserialize(
+        ba: &mut ByteBuffer,
+        type_def: &TypeDef,
+        schema: &Schema,
+        depth: usize,
+    ) -> Result<Struct, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::TooDeep {
+                type_name: type_def.name.clone(),
+                offset: ba.get_rpos(),
+            });
+        }
+
+        let mut result = Struct::new(&type_def.name);
+
+        for parent in type_def.parents.iter() {
+            let parent = schema.type_def(*parent);
+            PacketDecoder::deserialize_own(ba, parent, schema, depth, &mut result)?;
+        }
+        PacketDecoder::deserialize_own(ba, type_def, schema, depth, &mut result)?;
+
+        Ok(result)
+    }
+
+    // fields declared by the type itself, without the inherited ones
+    fn deserialize_own(
+        ba: &mut ByteBuffer,
+        type_def: &TypeDef,
+        schema: &Schema,
+        depth: usize,
+        result: &mut Struct,
+    ) -> Result<(), DecodeError> {
+        // flags are packed by eight, the first one in the lowest bit
+        for flags in type_def.bool_vars.chunks(8) {
+            let start = ba.get_rpos();
+            let box0 = ba.read_unsigned_byte()?;
+
+            for (i, name) in flags.iter().enumerate() {
+                let value = DecodedValue::Boolean(get_flag_boolean_byte(box0, i));
+                result.push(name, start..ba.get_rpos(), value);
+            }
+        }
+
+        for field in type_def.fields.iter() {
+            let start = ba.get_rpos();
+            let value = PacketDecoder::read_field(ba, field, schema, depth)?;
+            result.push(&field.name, start..ba.get_rpos(), value);
+        }
+
+        Ok(())
+    }
+
+    fn read_field(
+        ba: &mut ByteBuffer,
+        field: &FieldDef,
+        schema: &Schema,
+        depth: usize,
+    ) -> Result<DecodedValue, DecodeError> {
+        // optional values are preceded by a presence byte
+        if field.optional && ba.read_byte()? == 0 {
+            return Ok(DecodedValue::Null);
+        }
+
+        let size = match field.length {
+            Length::Single => return PacketDecoder::read_value(ba, field.kind, schema, depth),
+            Length::Fixed(size) => size as usize,
+            Length::Prefixed(length) => match get_atomic_length(ba, length)? {
+                Some(size) => size,
+                None => return Ok(DecodedValue::Null),
+            },
+        };
+
+        let mut arr_temp = Vec::new();
+        for _ in 0..size {
+            arr_temp.push(PacketDecoder::read_value(ba, field.kind, schema, depth)?);
+        }
+        Ok(DecodedValue::Array(arr_temp))
+    }
+
+    // `ID` fields are polymorphic, the concrete type id is written first
+    fn read_value(
+        ba: &mut ByteBuffer,
+        kind: FieldKind,
+        schema: &Schema,
+        depth: usize,
+    ) -> Result<DecodedValue, DecodeError> {
+        match kind {
+            FieldKind::Primitive(primitive) => ba.read(primitive),
+            FieldKind::Type(index) => Ok(DecodedValue::Struct(PacketDecoder::deserialize(
+                ba,
+                schema.type_def(index),
+                schema,
+                depth + 1,
+            )?)),
+            FieldKind::Polymorphic => {
+                let type_id = ba.read_unsigned_short()?;
+                let type_def = schema
+                    .type_by_id(type_id)
+                    .ok_or_else(|| DecodeError::UnknownType(format!("with id {}", type_id)))?;
+
+                Ok(DecodedValue::Polymorphic {
+                    type_id,
+                    value: PacketDecoder::deserialize(ba, type_def, schema, depth + 1)?,
+                })
+            }
+        }
+    }
+
+    /// Drop the partially received message of a flow, its remaining bytes were lost.
+    pub fn reset(&mut self, flow: &FlowKey) {
+        self.streams.remove(flow);
+    }
+
+    /// Forget a connection once both of its directions ended.
+    pub fn close(&mut self, flow: &FlowKey) {
+        self.streams.remove(flow);
+        self.streams.remove(&flow.reverse());
+        self.connections.remove(&flow.connection());
+    }
+
+    /// Messages decoded since the last `get_messages`, left in the queue.
+    pub fn pending_messages(&self) -> &[DofusPacket] {
+        &self.queue
+    }
+
+    pub fn get_messages(&mut self) -> Vec<DofusPacket> {
+        let queue = self.queue.clone();
+        self.queue.clear();
+        queue
+    }
+}
+
+/// Deserialise the body of a message read without its header,
+/// the counterpart of [`encode_message`](crate::encode_message).
+pub fn decode_body(
+    schema: &Schema,
+    message: &TypeDef,
+    payload: &[u8],
+) -> Result<Struct, DecodeError> {
+    PacketDecoder::deserialize(&mut ByteBuffer::from_bytes(payload), message, schema, 0)
+}
+
+// a negative length means no value was sent
+fn get_atomic_length(
+    ba: &mut ByteBuffer,
+    length: PrimitiveType,
+) -> Result<Option<usize>, DecodeError> {
+    Ok(ba
+        .read(length)?
+        .as_i64()
+        .and_then(|size| size.try_into().ok()))
+}
+
+// seconds since the epoch, with microseconds
+fn format_time(time: Duration) -> String {
+    format!("{}.{:06}", time.as_secs(), time.subsec_micros())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn get_flag_boolean_byte(a: u8, pos: usize) -> bool {
+    a & (1 << pos) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_encoder::BufferWriter;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    // ProtocolRequired { version: "2.64" }
+    const PROTOCOL_REQUIRED: &[u8] = &[0x8a, 0xe5, 0x06, 0x00, 0x04, 0x32, 0x2e, 0x36, 0x34];
+
+    fn flow() -> FlowKey {
+        FlowKey {
+            src_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            src_port: 5555,
+            dst_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            dst_port: 40000,
+        }
+    }
+
+    fn decode(segments: &[&[u8]], source: Source) -> Vec<DofusPacket> {
+        let mut decoder = PacketDecoder::new().unwrap();
+        for (index, segment) in segments.iter().enumerate() {
+            let time = Duration::from_secs(index as u64);
+            decoder.decode_packet(flow(), segment, source, time);
+        }
+        decoder.get_messages()
+    }
+
+    fn check_version(messages: &[DofusPacket]) {
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].name(), "ProtocolRequired");
+        assert_eq!(
+            messages[0]
+                .body()
+                .get("version")
+                .and_then(DecodedValue::as_str),
+            Some("2.64")
+        );
+    }
+
+    #[test]
+    fn decodes_whole_message() {
+        let messages = decode(&[PROTOCOL_REQUIRED], Source::Server);
+        check_version(&messages);
+        assert_eq!(messages[0].header().raw, "8ae506");
+    }
+
+    #[test]
+    fn decodes_message_split_anywhere() {
+        for at in 1..PROTOCOL_REQUIRED.len() {
+            let (first, second) = PROTOCOL_REQUIRED.split_at(at);
+            let messages = decode(&[first, second], Source::Server);
+
+            check_version(&messages);
+            assert_eq!(messages[0].header().raw, "8ae506");
+            assert_eq!(messages[0].time, format_time(Duration::ZERO));
+            assert_eq!(messages[0].end_time, format_time(Duration::from_secs(1)));
+        }
+    }
+
+    #[test]
+    fn decodes_client_header_byte_by_byte() {
+        let mut bytes = vec![0x8a, 0xe5, 0x00, 0x00, 0x00, 0x2a];
+        bytes.extend_from_slice(&PROTOCOL_REQUIRED[2..]);
+        let segments: Vec<&[u8]> = bytes.chunks(1).collect();
+
+        let messages = decode(&segments, Source::Client);
+        check_version(&messages);
+        assert_eq!(messages[0].header().instance_id, Some(42));
+        assert_eq!(messages[0].header().raw, "8ae50000002a06");
+    }
+
+    #[test]
+    fn decodes_header_split_after_message() {
+        let mut bytes = PROTOCOL_REQUIRED.to_vec();
+        bytes.extend_from_slice(PROTOCOL_REQUIRED);
+        let (first, second) = bytes.split_at(PROTOCOL_REQUIRED.len() + 1);
+
+        let messages = decode(&[first, second], Source::Server);
+        assert_eq!(messages.len(), 2);
+        check_version(&messages[1..]);
+    }
+
+    #[test]
+    fn forgets_closed_connections() {
+        let mut decoder = PacketDecoder::new().unwrap();
+        decoder.decode_packet(
+            flow(),
+            &PROTOCOL_REQUIRED[..5],
+            Source::Server,
+            Duration::ZERO,
+        );
+        assert_eq!(decoder.connections.len(), 1);
+        assert_eq!(decoder.streams.len(), 1);
+
+        decoder.close(&flow().reverse());
+        assert!(decoder.connections.is_empty());
+        assert!(decoder.streams.is_empty());
+    }
+
+    #[test]
+    fn decodes_more_than_eight_bool_vars() {
+        // SetCharacterRestrictionsMessage, its restrictions carry 20 flags over 3 bytes
+        let mut bytes = vec![0x94, 0x09, 11];
+        bytes.extend_from_slice(&1.0f64.to_be_bytes());
+        bytes.extend_from_slice(&[0b1010_0101, 0b0000_0011, 0b0000_1001]);
+        let set = [0, 2, 5, 7, 8, 9, 16, 19];
+
+        let messages = decode(&[&bytes], Source::Server);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].name(), "SetCharacterRestrictionsMessage");
+
+        let restrictions = messages[0]
+            .body()
+            .get("restrictions")
+            .and_then(DecodedValue::as_struct)
+            .unwrap();
+        assert_eq!(restrictions.fields.len(), 20);
+        assert_eq!(restrictions.fields[0].name, "cantBeAggressed");
+        assert_eq!(restrictions.fields[19].name, "cantAttackMonster");
+        for (index, flag) in restrictions.fields.iter().enumerate() {
+            let value = DecodedValue::Boolean(set.contains(&index));
+            assert_eq!(flag.value, value, "flag {} {}", index, flag.name);
+            assert_eq!((flag.offset, flag.length), (8 + index / 8, 1));
+        }
+    }
+
+    #[test]
+    fn decodes_fields_after_absent_optional() {
+        // GameRolePlayArenaUpdatePlayerInfosMessage, ArenaRankInfos without its ranking
+        let bytes = [
+            0x79, 0xb1, 19,   // header
+            0x00, // ranking absent
+            0x01, // leagueRanking present
+            0xac, 0x02, // rank 300
+            0x05, // leagueId 5
+            0xfe, 0xff, 0x03, // leaguePoints -2
+            0xe8, 0x07, // totalLeaguePoints 1000
+            0x00, 0x00, 0x00, 0x2a, // ladderPosition 42
+            0x07, // victoryCount 7
+            0xc8, 0x01, // fightcount 200
+            0x00, 0x03, // numFightNeededForLadder 3
+        ];
+
+        let messages = decode(&[&bytes], Source::Server);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].name(),
+            "GameRolePlayArenaUpdatePlayerInfosMessage"
+        );
+
+        let solo = messages[0]
+            .body()
+            .get("solo")
+            .and_then(DecodedValue::as_struct)
+            .unwrap();
+        assert_eq!(solo.get("ranking"), Some(&DecodedValue::Null));
+        assert_eq!(solo.get("victoryCount"), Some(&DecodedValue::VarUhShort(7)));
+        assert_eq!(solo.get("fightcount"), Some(&DecodedValue::VarUhShort(200)));
+        assert_eq!(
+            solo.get("numFightNeededForLadder"),
+            Some(&DecodedValue::Short(3))
+        );
+
+        let league = solo
+            .get("leagueRanking")
+            .and_then(DecodedValue::as_struct)
+            .unwrap();
+        assert_eq!(league.get("rank"), Some(&DecodedValue::VarUhShort(300)));
+        assert_eq!(league.get("leagueId"), Some(&DecodedValue::VarUhShort(5)));
+        assert_eq!(
+            league.get("leaguePoints"),
+            Some(&DecodedValue::VarShort(-2))
+        );
+        assert_eq!(
+            league.get("totalLeaguePoints"),
+            Some(&DecodedValue::VarShort(1000))
+        );
+        assert_eq!(league.get("ladderPosition"), Some(&DecodedValue::Int(42)));
+    }
+
+    #[test]
+    fn serializes_nested_values() {
+        // GameRolePlayArenaUpdatePlayerInfosAllQueuesMessage, three ArenaRankInfos
+        let mut bytes = vec![0x74, 0xc1, 18];
+        for (victories, fights, needed) in [(1, 2, 3), (4, 5, 6), (7, 8, 9)] {
+            bytes.extend_from_slice(&[0x00, 0x00, victories, fights, 0x00, needed]);
+        }
+        // PlayerStatusUpdateMessage, its status is a PlayerStatusExtended
+        bytes.extend_from_slice(&[0x03, 0x95, 13, 0x00, 0x00, 0x00, 0x07, 0x2a]);
+        bytes.extend_from_slice(&[0x13, 0xb8, 0x01, 0x00, 0x03, b'a', b'f', b'k']);
+
+        let messages = decode(&[&bytes], Source::Server);
+        assert_eq!(messages.len(), 2);
+
+        let json = serde_json::to_value(&messages[0]).unwrap();
+        let queue = |victories, fights, needed| {
+            serde_json::json!({
+                "ranking": null,
+                "leagueRanking": null,
+                "victoryCount": victories,
+                "fightcount": fights,
+                "numFightNeededForLadder": needed,
+            })
+        };
+        assert_eq!(json["body"]["solo"], queue(1, 2, 3));
+        assert_eq!(json["body"]["team"], queue(4, 5, 6));
+        assert_eq!(json["body"]["duel"], queue(7, 8, 9));
+
+        let json = serde_json::to_value(&messages[1]).unwrap();
+        assert_eq!(
+            json["body"],
+            serde_json::json!({
+                "accountId": 7,
+                "playerId": 42,
+                "status": {
+                    "__type__": "PlayerStatusExtended",
+                    "statusId": 1,
+                    "message": "afk",
+                },
+            })
+        );
+    }
+
+    // GameRolePlayShowActorMessage whose actor is positioned by itself, `depth` times
+    fn self_nested_actor(depth: usize) -> Vec<u8> {
+        let mut body = vec![];
+        for _ in 0..depth {
+            // GameContextActorPositionInformations, its disposition comes next
+            body.extend_from_slice(&7625u16.to_be_bytes());
+            body.extend_from_slice(&1.0f64.to_be_bytes());
+        }
+        // EntityDispositionInformations { cellId: 300, direction: 1 }
+        body.extend_from_slice(&[0x03, 0xf5, 0x01, 0x2c, 0x01]);
+        body
+    }
+
+    fn parse_actor(body: &[u8]) -> Result<DofusPacket, DecodeError> {
+        let schema = Schema::embedded().unwrap();
+        let header = MessageHeader {
+            id: 3674,
+            length_type: 3,
+            length: body.len(),
+            instance_id: None,
+            raw: String::new(),
+        };
+        let mut ba = ByteBuffer::from_bytes(body);
+        PacketDecoder::parse_ba_to_object(&mut ba, &header, Source::Server, &schema)
+    }
+
+    #[test]
+    fn bounds_nesting() {
+        let message = parse_actor(&self_nested_actor(MAX_DEPTH - 1)).unwrap();
+        let mut value = message.body().get("informations").unwrap();
+        for _ in 0..MAX_DEPTH - 1 {
+            value = value.as_struct().unwrap().get("disposition").unwrap();
+        }
+        let disposition = value.as_struct().unwrap();
+        assert_eq!(disposition.type_name, "EntityDispositionInformations");
+        assert_eq!(disposition.get("cellId"), Some(&DecodedValue::Short(300)));
+
+        assert!(matches!(
+            parse_actor(&self_nested_actor(MAX_DEPTH)),
+            Err(DecodeError::TooDeep { offset, .. }) if offset == 10 * MAX_DEPTH + 2
+        ));
+        // deep enough to overflow the stack without the bound
+        assert!(matches!(
+            parse_actor(&self_nested_actor(1_000_000)),
+            Err(DecodeError::TooDeep { .. })
+        ));
+    }
+
+    fn written<F: FnOnce(&mut ByteBuffer)>(write: F) -> Vec<u8> {
+        let mut ba = ByteBuffer::new();
+        write(&mut ba);
+        ba.to_bytes()
+    }
+
+    #[test]
+    fn var_long_round_trips() {
+        let safe = 1i64 << 53;
+        for &value in &[0, 1, -1, safe - 1, safe + 1, -safe - 1, i64::MIN, i64::MAX] {
+            let bytes = written(|ba| ba.write_var_long(value));
+            let mut ba = ByteBuffer::from_bytes(&bytes);
+            assert_eq!(ba.read_var_long().unwrap(), value);
+            assert_eq!(ba.bytes_available(), 0);
+        }
+
+        for &value in &[0, 1, (1u64 << 53) - 1, (1u64 << 53) + 1, u64::MAX] {
+            let bytes = written(|ba| ba.write_var_uh_long(value));
+            let mut ba = ByteBuffer::from_bytes(&bytes);
+            assert_eq!(ba.read_var_uh_long().unwrap(), value);
+            assert_eq!(ba.bytes_available(), 0);
+        }
+    }
+
+    #[test]
+    fn var_long_uses_two_complement() {
+        let mut max = vec![0xff; 9];
+        max.push(0x01);
+        assert_eq!(written(|ba| ba.write_var_uh_long(u64::MAX)), max);
+        assert_eq!(written(|ba| ba.write_var_long(-1)), max);
+
+        let mut min = vec![0x80; 9];
+        min.push(0x01);
+        assert_eq!(written(|ba| ba.write_var_long(i64::MIN)), min);
+
+        assert_eq!(written(|ba| ba.write_var_long(0)), [0x00]);
+        assert_eq!(written(|ba| ba.write_var_long(1)), [0x01]);
+    }
+
+    #[test]
+    fn var_long_rejects_extra_bits() {
+        // the tenth byte only has room for the last bit
+        let mut overflow = vec![0xff; 9];
+        overflow.push(0x02);
+        let mut ba = ByteBuffer::from_bytes(&overflow);
+        assert!(matches!(
+            ba.read_var_long(),
+            Err(DecodeError::InvalidValue { offset: 0, .. })
+        ));
+
+        // and can't announce an eleventh one
+        let mut eleven = vec![0x80; 9];
+        eleven.extend_from_slice(&[0x81, 0x00]);
+        let mut ba = ByteBuffer::from_bytes(&eleven);
+        assert!(matches!(
+            ba.read_var_uh_long(),
+            Err(DecodeError::InvalidValue { offset: 0, .. })
+        ));
+
+        let mut ba = ByteBuffer::from_bytes(&[0x80, 0x80]);
+        assert!(matches!(
+            ba.read_var_long(),
+            Err(DecodeError::OutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn var_int_and_short_round_trip() {
+        for &value in &[0, 1, -1, 127, 128, -128, i32::MIN, i32::MAX] {
+            let bytes = written(|ba| ba.write_var_int(value));
+            let mut ba = ByteBuffer::from_bytes(&bytes);
+            assert_eq!(ba.read_var_int().unwrap(), value);
+            assert_eq!(ba.bytes_available(), 0);
+        }
+        assert_eq!(
+            written(|ba| ba.write_var_int(-1)),
+            [0xff, 0xff, 0xff, 0xff, 0x0f]
+        );
+        assert_eq!(
+            written(|ba| ba.write_var_int(i32::MIN)),
+            [0x80, 0x80, 0x80, 0x80, 0x08]
+        );
+
+        for &value in &[0, 1, -1, 127, 128, -128, i16::MIN, i16::MAX] {
+            let bytes = written(|ba| ba.write_var_short(value));
+            let mut ba = ByteBuffer::from_bytes(&bytes);
+            assert_eq!(ba.read_var_short().unwrap(), value);
+            assert_eq!(ba.bytes_available(), 0);
+        }
+        assert_eq!(written(|ba| ba.write_var_short(-1)), [0xff, 0xff, 0x03]);
+        assert_eq!(
+            written(|ba| ba.write_var_short(i16::MIN)),
+            [0x80, 0x80, 0x02]
+        );
+
+        // a VarShort has no room for a 17th bit
+        let mut ba = ByteBuffer::from_bytes(&[0xff, 0xff, 0x07]);
+        assert!(ba.read_var_short().is_err());
+    }
+
+    #[test]
+    fn floats_keep_their_sign() {
+        for &value in &[0.0, -0.5, 1.5e300, -1.5e300, f64::MIN, f64::MAX] {
+            let mut ba = ByteBuffer::from_bytes(&value.to_be_bytes());
+            assert_eq!(ba.read_double().unwrap(), value);
+
+            let double = DecodedValue::Double(value);
+            let bytes = written(|ba| ba.write_primitive(PrimitiveType::Double, &double).unwrap());
+            assert_eq!(bytes, value.to_be_bytes());
+        }
+
+        for &value in &[0.0, -0.5, 3.25, -3.25e30, f32::MIN, f32::MAX] {
+            let mut ba = ByteBuffer::from_bytes(&value.to_be_bytes());
+            assert_eq!(ba.read_float().unwrap(), value);
+
+            let float = DecodedValue::Float(value);
+            let bytes = written(|ba| ba.write_primitive(PrimitiveType::Float, &float).unwrap());
+            assert_eq!(bytes, value.to_be_bytes());
+        }
+    }
+}
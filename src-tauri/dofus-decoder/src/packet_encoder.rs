@@ -0,0 +1,594 @@
+use crate::{
+    error::DecodeError,
+    schema::{FieldDef, FieldKind, Length, PrimitiveType, Schema, TypeDef},
+    value::{DecodedValue, Struct},
+};
+use bytebuffer::ByteBuffer;
+use std::convert::TryFrom;
+
+/// Writes the protocol primitives, the counterpart of the decoder reads.
+pub trait BufferWriter {
+    fn write_primitive(
+        &mut self,
+        primitive: PrimitiveType,
+        value: &DecodedValue,
+    ) -> Result<(), String>;
+    fn write_utf(&mut self, value: &str) -> Result<(), String>;
+    fn write_var_int(&mut self, value: i32);
+    fn write_var_uh_int(&mut self, value: u32);
+    fn write_var_short(&mut self, value: i16);
+    fn write_var_uh_short(&mut self, value: u16);
+    fn write_var_uh_long(&mut self, value: u64);
+    fn write_var_long(&mut self, value: i64);
+    fn write_var_bits(&mut self, value: u64);
+}
+
+impl BufferWriter for ByteBuffer {
+    // the value has to be of the wire type of the field
+    fn write_primitive(
+        &mut self,
+        primitive: PrimitiveType,
+        value: &DecodedValue,
+    ) -> Result<(), String> {
+        match (primitive, value) {
+            (PrimitiveType::Boolean, DecodedValue::Boolean(v)) => self.write_u8(u8::from(*v)),
+            (PrimitiveType::Byte, DecodedValue::Byte(v)) => self.write_i8(*v),
+            (PrimitiveType::UnsignedByte, DecodedValue::UnsignedByte(v)) => self.write_u8(*v),
+            (PrimitiveType::Short, DecodedValue::Short(v)) => self.write_i16(*v),
+            (PrimitiveType::UnsignedShort, DecodedValue::UnsignedShort(v)) => self.write_u16(*v),
+            (PrimitiveType::Int, DecodedValue::Int(v)) => self.write_i32(*v),
+            (PrimitiveType::UnsignedInt, DecodedValue::UnsignedInt(v)) => self.write_u32(*v),
+            (PrimitiveType::Float, DecodedValue::Float(v)) => self.write_f32(*v),
+            (PrimitiveType::Double, DecodedValue::Double(v)) => self.write_f64(*v),
+            (PrimitiveType::Utf, DecodedValue::Utf(v)) => self.write_utf(v)?,
+            (PrimitiveType::ByteArray, DecodedValue::ByteArray(v)) => {
+                let len = u32::try_from(v.len()).map_err(|_| String::from("too long"))?;
+                self.write_var_uh_int(len);
+                self.write_bytes(v);
+            }
+            (PrimitiveType::VarShort, DecodedValue::VarShort(v)) => self.write_var_short(*v),
+            (PrimitiveType::VarUhShort, DecodedValue::VarUhShort(v)) => self.write_var_uh_short(*v),
+            (PrimitiveType::VarInt, DecodedValue::VarInt(v)) => self.write_var_int(*v),
+            (PrimitiveType::VarUhInt, DecodedValue::VarUhInt(v)) => self.write_var_uh_int(*v),
+            (PrimitiveType::VarLong, DecodedValue::VarLong(v)) => self.write_var_long(*v),
+            (PrimitiveType::VarUhLong, DecodedValue::VarUhLong(v)) => self.write_var_uh_long(*v),
+            (primitive, value) => {
+                return Err(format!("expected {}, got {:?}", primitive.name(), value))
+            }
+        }
+        Ok(())
+    }
+    fn write_utf(&mut self, value: &str) -> Result<(), String> {
+        let len = u16::try_from(value.len()).map_err(|_| String::from("too long"))?;
+        self.write_u16(len);
+        self.write_bytes(value.as_bytes());
+        Ok(())
+    }
+    fn write_var_int(&mut self, value: i32) {
+        self.write_var_bits(u64::from(value as u32))
+    }
+    fn write_var_uh_int(&mut self, value: u32) {
+        self.write_var_bits(value.into())
+    }
+    fn write_var_short(&mut self, value: i16) {
+        self.write_var_bits(u64::from(value as u16))
+    }
+    fn write_var_uh_short(&mut self, value: u16) {
+        self.write_var_bits(value.into())
+    }
+    fn write_var_uh_long(&mut self, value: u64) {
+        self.write_var_bits(value)
+    }
+    fn write_var_long(&mut self, value: i64) {
+        self.write_var_bits(value as u64)
+    }
+    fn write_var_bits(&mut self, value: u64) {
+        let mut value = value;
+        while value >= 128 {
+            self.write_u8((value & 127) as u8 | 128);
+            value >>= 7;
+        }
+        self.write_u8(value as u8);
+    }
+}
+
+/// Serialise a message as sent on the wire, header included.
+///
+/// `body` has the shape of [`DofusPacket::body`](crate::DofusPacket::body),
+/// the `instance_id` is only sent by the client.
+pub fn encode_message(
+    schema: &Schema,
+    message: &TypeDef,
+    body: &Struct,
+    instance_id: Option<u32>,
+) -> Result<Vec<u8>, DecodeError> {
+    let mut payload = ByteBuffer::new();
+    encode_struct(&mut payload, message, body, schema)?;
+    let payload = payload.to_bytes();
+
+    // the smallest number of bytes able to hold the length
+    let length_type: u16 = match payload.len() {
+        0 => 0,
+        1..=0xff => 1,
+        0x100..=0xffff => 2,
+        0x1_0000..=0xff_ffff => 3,
+        len => {
+            return Err(DecodeError::InvalidBody {
+                field: message.name.clone(),
+                reason: format!("{} bytes do not fit in a message", len),
+            })
+        }
+    };
+
+    let mut ba = ByteBuffer::new();
+    ba.write_u16(message.protocol_id << 2 | length_type);
+    if let Some(instance_id) = instance_id {
+        ba.write_u32(instance_id);
+    }
+    for i in (0..length_type).rev() {
+        ba.write_u8((payload.len() >> (8 * i)) as u8);
+    }
+    ba.write_bytes(&payload);
+
+    Ok(ba.to_bytes())
+}
+
+fn encode_struct(
+    ba: &mut ByteBuffer,
+    type_def: &TypeDef,
+    value: &Struct,
+    schema: &Schema,
+) -> Result<(), DecodeError> {
+    for parent in type_def.parents.iter() {
+        encode_own(ba, schema.type_def(*parent), value, schema)?;
+    }
+    encode_own(ba, type_def, value, schema)
+}
+
+// fields declared by the type itself, without the inherited ones
+fn encode_own(
+    ba: &mut ByteBuffer,
+    type_def: &TypeDef,
+    value: &Struct,
+    schema: &Schema,
+) -> Result<(), DecodeError> {
+    let field_value = |name: &str| {
+        value.get(name).ok_or_else(|| DecodeError::InvalidBody {
+            field: format!("{}.{}", type_def.name, name),
+            reason: String::from("missing"),
+        })
+    };
+
+    for flags in type_def.bool_vars.chunks(8) {
+        let mut box0 = 0u8;
+        for (i, name) in flags.iter().enumerate() {
+            match field_value(name)? {
+                DecodedValue::Boolean(true) => box0 |= 1 << i,
+                DecodedValue::Boolean(false) => {}
+                other => {
+                    return Err(DecodeError::InvalidBody {
+                        field: format!("{}.{}", type_def.name, name),
+                        reason: format!("expected Boolean, got {:?}", other),
+                    })
+                }
+            }
+        }
+        ba.write_u8(box0);
+    }
+
+    for field in type_def.fields.iter() {
+        let name = format!("{}.{}", type_def.name, field.name);
+        encode_field(ba, field, &name, field_value(&field.name)?, schema)?;
+    }
+
+    Ok(())
+}
+
+fn encode_field(
+    ba: &mut ByteBuffer,
+    field: &FieldDef,
+    name: &str,
+    value: &DecodedValue,
+    schema: &Schema,
+) -> Result<(), DecodeError> {
+    let invalid = |reason: String| DecodeError::InvalidBody {
+        field: name.to_string(),
+        reason,
+    };
+
+    if field.optional {
+        if *value == DecodedValue::Null {
+            ba.write_u8(0);
+            return Ok(());
+        }
+        ba.write_u8(1);
+    }
+
+    if field.length == Length::Single {
+        return encode_value(ba, field.kind, name, value, schema);
+    }
+
+    if let (Length::Prefixed(length), DecodedValue::Null) = (field.length, value) {
+        let size = absent_length(length)
+            .ok_or_else(|| invalid(format!("a {} size can't be negative", length.name())))?;
+        return ba.write_primitive(length, &size).map_err(invalid);
+    }
+
+    let items = value
+        .as_array()
+        .ok_or_else(|| invalid(format!("expected an array, got {:?}", value)))?;
+
+    match field.length {
+        Length::Fixed(size) if items.len() != size as usize => {
+            return Err(invalid(format!(
+                "expected {} items, got {}",
+                size,
+                items.len()
+            )));
+        }
+        Length::Prefixed(length) => {
+            let size = length_value(length, items.len())
+                .ok_or_else(|| invalid(format!("{} items can't be counted", items.len())))?;
+            ba.write_primitive(length, &size).map_err(invalid)?;
+        }
+        _ => {}
+    }
+
+    for item in items.iter() {
+        encode_value(ba, field.kind, name, item, schema)?;
+    }
+    Ok(())
+}
+
+fn encode_value(
+    ba: &mut ByteBuffer,
+    kind: FieldKind,
+    name: &str,
+    value: &DecodedValue,
+    schema: &Schema,
+) -> Result<(), DecodeError> {
+    let invalid = |reason: String| DecodeError::InvalidBody {
+        field: name.to_string(),
+        reason,
+    };
+
+    match (kind, value) {
+        (FieldKind::Primitive(primitive), value) => {
+            ba.write_primitive(primitive, value).map_err(invalid)
+        }
+        (FieldKind::Type(index), DecodedValue::Struct(value)) => {
+            encode_struct(ba, schema.type_def(index), value, schema)
+        }
+        (FieldKind::Polymorphic, DecodedValue::Polymorphic { type_id, value }) => {
+            let type_def = schema
+                .type_by_id(*type_id)
+                .ok_or_else(|| DecodeError::UnknownType(format!("with id {}", type_id)))?;
+            ba.write_u16(*type_id);
+            encode_struct(ba, type_def, value, schema)
+        }
+        (FieldKind::Type(index), value) => Err(invalid(format!(
+            "expected {}, got {:?}",
+            schema.type_def(index).name,
+            value
+        ))),
+        (FieldKind::Polymorphic, value) => Err(invalid(format!(
+            "expected a polymorphic value, got {:?}",
+            value
+        ))),
+    }
+}
+
+// array sizes are written with the integer type of the definition
+fn length_value(length: PrimitiveType, size: usize) -> Option<DecodedValue> {
+    Some(match length {
+        PrimitiveType::Byte => DecodedValue::Byte(i8::try_from(size).ok()?),
+        PrimitiveType::UnsignedByte => DecodedValue::UnsignedByte(u8::try_from(size).ok()?),
+        PrimitiveType::Short => DecodedValue::Short(i16::try_from(size).ok()?),
+        PrimitiveType::UnsignedShort => DecodedValue::UnsignedShort(u16::try_from(size).ok()?),
+        PrimitiveType::Int => DecodedValue::Int(i32::try_from(size).ok()?),
+        PrimitiveType::UnsignedInt => DecodedValue::UnsignedInt(u32::try_from(size).ok()?),
+        PrimitiveType::VarShort => DecodedValue::VarShort(i16::try_from(size).ok()?),
+        PrimitiveType::VarUhShort => DecodedValue::VarUhShort(u16::try_from(size).ok()?),
+        PrimitiveType::VarInt => DecodedValue::VarInt(i32::try_from(size).ok()?),
+        PrimitiveType::VarUhInt => DecodedValue::VarUhInt(u32::try_from(size).ok()?),
+        PrimitiveType::VarLong => DecodedValue::VarLong(i64::try_from(size).ok()?),
+        PrimitiveType::VarUhLong => DecodedValue::VarUhLong(u64::try_from(size).ok()?),
+        _ => return None,
+    })
+}
+
+// size of an array decoded as `Null`, only signed types have one
+fn absent_length(length: PrimitiveType) -> Option<DecodedValue> {
+    Some(match length {
+        PrimitiveType::Byte => DecodedValue::Byte(-1),
+        PrimitiveType::Short => DecodedValue::Short(-1),
+        PrimitiveType::Int => DecodedValue::Int(-1),
+        PrimitiveType::VarShort => DecodedValue::VarShort(-1),
+        PrimitiveType::VarInt => DecodedValue::VarInt(-1),
+        PrimitiveType::VarLong => DecodedValue::VarLong(-1),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet_decoder::{Buffer, PacketDecoder, Source},
+        schema::SchemaRegistry,
+        tcp_reassembly::FlowKey,
+    };
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    // xorshift, the bodies only have to vary from one field to the next
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    fn primitive(rng: &mut Rng, primitive: PrimitiveType) -> DecodedValue {
+        let x = rng.next();
+        match primitive {
+            PrimitiveType::Boolean => DecodedValue::Boolean(x & 1 == 1),
+            PrimitiveType::Byte => DecodedValue::Byte(x as i8),
+            PrimitiveType::UnsignedByte => DecodedValue::UnsignedByte(x as u8),
+            PrimitiveType::Short => DecodedValue::Short(x as i16),
+            PrimitiveType::UnsignedShort => DecodedValue::UnsignedShort(x as u16),
+            PrimitiveType::Int => DecodedValue::Int(x as i32),
+            PrimitiveType::UnsignedInt => DecodedValue::UnsignedInt(x as u32),
+            PrimitiveType::Float => DecodedValue::Float(f32::from(x as i16) / 7.0),
+            PrimitiveType::Double => DecodedValue::Double(x as i64 as f64 / 3.0),
+            PrimitiveType::Utf => DecodedValue::Utf(format!("é{}", x % 1000)),
+            PrimitiveType::ByteArray => {
+                DecodedValue::ByteArray((0..x % 5).map(|i| i as u8).collect())
+            }
+            PrimitiveType::VarShort => DecodedValue::VarShort(x as i16),
+            PrimitiveType::VarUhShort => DecodedValue::VarUhShort(x as u16),
+            PrimitiveType::VarInt => DecodedValue::VarInt(x as i32),
+            PrimitiveType::VarUhInt => DecodedValue::VarUhInt(x as u32),
+            PrimitiveType::VarLong => DecodedValue::VarLong(x as i64),
+            PrimitiveType::VarUhLong => DecodedValue::VarUhLong(x),
+        }
+    }
+
+    fn value(rng: &mut Rng, schema: &Schema, kind: FieldKind, depth: usize) -> DecodedValue {
+        match kind {
+            FieldKind::Primitive(kind) => primitive(rng, kind),
+            FieldKind::Type(index) => {
+                DecodedValue::Struct(body(rng, schema, schema.type_def(index), depth + 1))
+            }
+            FieldKind::Polymorphic => {
+                // messages and types may share an id, only types can be sent there
+                let types: Vec<&TypeDef> = schema
+                    .types()
+                    .iter()
+                    .filter(|type_def| {
+                        let found = schema.type_by_id(type_def.protocol_id);
+                        found.map(|found| &found.name) == Some(&type_def.name)
+                    })
+                    .collect();
+                let type_def = types[rng.next() as usize % types.len()];
+                DecodedValue::Polymorphic {
+                    type_id: type_def.protocol_id,
+                    value: body(rng, schema, type_def, depth + 1),
+                }
+            }
+        }
+    }
+
+    // arrays and optional values stop being filled past a few levels
+    fn body(rng: &mut Rng, schema: &Schema, type_def: &TypeDef, depth: usize) -> Struct {
+        let deep = depth > 3;
+        let mut body = Struct::new(&type_def.name);
+
+        let parents = type_def.parents.iter().map(|index| schema.type_def(*index));
+        for own in parents.chain(std::iter::once(type_def)) {
+            for name in own.bool_vars.iter() {
+                body.push(name, 0..0, DecodedValue::Boolean(rng.next() & 1 == 1));
+            }
+
+            for field in own.fields.iter() {
+                let absent = field.optional && (deep || rng.next() & 1 == 0);
+                let value = match field.length {
+                    _ if absent => DecodedValue::Null,
+                    Length::Single => value(rng, schema, field.kind, depth),
+                    Length::Fixed(size) => DecodedValue::Array(
+                        (0..size)
+                            .map(|_| value(rng, schema, field.kind, depth))
+                            .collect(),
+                    ),
+                    Length::Prefixed(length) => match rng.next() % 4 {
+                        0 if absent_length(length).is_some() => DecodedValue::Null,
+                        _ if deep => DecodedValue::Array(vec![]),
+                        size => DecodedValue::Array(
+                            (0..size)
+                                .map(|_| value(rng, schema, field.kind, depth))
+                                .collect(),
+                        ),
+                    },
+                };
+                body.push(&field.name, 0..0, value);
+            }
+        }
+        body
+    }
+
+    // spans of generated bodies are left empty
+    fn clear_spans(body: &mut Struct) {
+        for field in body.fields.iter_mut() {
+            field.offset = 0;
+            field.length = 0;
+            clear_value(&mut field.value);
+        }
+    }
+
+    fn clear_value(value: &mut DecodedValue) {
+        match value {
+            DecodedValue::Struct(value) | DecodedValue::Polymorphic { value, .. } => {
+                clear_spans(value)
+            }
+            DecodedValue::Array(items) => items.iter_mut().for_each(clear_value),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        let schema = Schema::embedded().unwrap();
+        let registry = SchemaRegistry::new("2.64", Schema::embedded().unwrap());
+        let mut decoder = PacketDecoder::with_schemas(registry);
+        let client = FlowKey {
+            src_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            src_port: 40000,
+            dst_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst_port: 5555,
+        };
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        let messages = schema.types().iter().filter(|type_def| {
+            schema.message(type_def.protocol_id).map(|m| &m.name) == Some(&type_def.name)
+        });
+        let mut count = 0;
+        for message in messages {
+            for (source, flow, instance_id) in [
+                (Source::Server, client.reverse(), None),
+                (Source::Client, client, Some(42)),
+            ] {
+                let body = body(&mut rng, &schema, message, 0);
+                let bytes = encode_message(&schema, message, &body, instance_id).unwrap();
+
+                decoder.decode_packet(flow, &bytes, source, Duration::ZERO);
+                let mut decoded = decoder.get_messages();
+                assert_eq!(decoded.len(), 1, "{} was not decoded", message.name);
+                assert_eq!(decoded[0].name(), message.name);
+                assert_eq!(decoded[0].header().instance_id, instance_id);
+
+                let encoded = encode_message(&schema, message, decoded[0].body(), instance_id);
+                assert_eq!(
+                    encoded.unwrap(),
+                    bytes,
+                    "{} encodes differently",
+                    message.name
+                );
+
+                let decoded = decoded.remove(0);
+                let mut decoded_body = decoded.body().clone();
+                clear_spans(&mut decoded_body);
+                assert_eq!(decoded_body, body, "{} decodes differently", message.name);
+            }
+            count += 1;
+        }
+        assert!(count > 1000);
+    }
+
+    // bytes taken by `bits` sent 7 by 7
+    fn var_len(bits: u64) -> usize {
+        let significant = 64 - bits.leading_zeros() as usize;
+        ((significant + 6) / 7).max(1)
+    }
+
+    #[test]
+    fn primitives_round_trip() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+        // both sides of every 7-bit byte boundary, and their negations
+        let mut samples = vec![];
+        for bits in (0..64).step_by(7) {
+            let edge = 1u64 << bits;
+            samples.extend_from_slice(&[edge - 1, edge, !(edge - 1), !edge]);
+        }
+        // then values of every bit length
+        samples.extend((0..10_000).map(|_| {
+            let shift = rng.next() % 64;
+            rng.next() >> shift
+        }));
+
+        for x in samples {
+            let short = u64::from(x as u16);
+            let int = u64::from(x as u32);
+            let values = [
+                (
+                    PrimitiveType::VarShort,
+                    DecodedValue::VarShort(x as i16),
+                    var_len(short),
+                ),
+                (
+                    PrimitiveType::VarUhShort,
+                    DecodedValue::VarUhShort(x as u16),
+                    var_len(short),
+                ),
+                (
+                    PrimitiveType::VarInt,
+                    DecodedValue::VarInt(x as i32),
+                    var_len(int),
+                ),
+                (
+                    PrimitiveType::VarUhInt,
+                    DecodedValue::VarUhInt(x as u32),
+                    var_len(int),
+                ),
+                (
+                    PrimitiveType::Float,
+                    DecodedValue::Float(f32::from_bits(x as u32)),
+                    4,
+                ),
+                (
+                    PrimitiveType::Double,
+                    DecodedValue::Double(f64::from_bits(x)),
+                    8,
+                ),
+            ];
+
+            for (primitive, value, len) in values {
+                let mut ba = ByteBuffer::new();
+                ba.write_primitive(primitive, &value).unwrap();
+                let bytes = ba.to_bytes();
+                assert_eq!(bytes.len(), len, "{:?} takes {:?}", value, bytes);
+
+                let mut ba = ByteBuffer::from_bytes(&bytes);
+                let decoded = ba.read(primitive).unwrap();
+                assert_eq!(ba.bytes_available(), 0);
+                // compared by bits, NaN isn't equal to itself
+                let same = match (&decoded, &value) {
+                    (DecodedValue::Float(a), DecodedValue::Float(b)) => a.to_bits() == b.to_bits(),
+                    (DecodedValue::Double(a), DecodedValue::Double(b)) => {
+                        a.to_bits() == b.to_bits()
+                    }
+                    _ => decoded == value,
+                };
+                assert!(same, "{:?} read back as {:?}", value, decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn absent_array_is_a_negative_size() {
+        let field = FieldDef {
+            name: String::from("values"),
+            kind: FieldKind::Primitive(PrimitiveType::Int),
+            length: Length::Prefixed(PrimitiveType::Short),
+            optional: false,
+        };
+        let schema = Schema::embedded().unwrap();
+
+        let mut ba = ByteBuffer::new();
+        encode_field(&mut ba, &field, "values", &DecodedValue::Null, &schema).unwrap();
+        assert_eq!(ba.to_bytes(), [0xff, 0xff]);
+
+        let field = FieldDef {
+            length: Length::Prefixed(PrimitiveType::VarUhShort),
+            ..field
+        };
+        let mut ba = ByteBuffer::new();
+        assert!(encode_field(&mut ba, &field, "values", &DecodedValue::Null, &schema).is_err());
+    }
+}
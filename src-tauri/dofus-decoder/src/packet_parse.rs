@@ -0,0 +1,773 @@
+use crate::ip_reassembly::FragmentReassembler;
+use pcap::Linktype;
+use pktparse::arp::ArpPacket;
+use pktparse::ethernet::{EtherType, EthernetFrame};
+use pktparse::ip::IPProtocol;
+use pktparse::ipv4::IPv4Header;
+use pktparse::ipv6::IPv6Header;
+use pktparse::tcp::TcpHeader;
+use pktparse::udp::UdpHeader;
+use pktparse::*;
+use std::cmp::min;
+use std::string::ToString;
+use std::time::Duration;
+use tls_parser::TlsMessage;
+
+use serde::{Deserialize, Serialize};
+
+// DLT_RAW, whose value differs between platforms, before LINKTYPE_RAW was defined
+const DLT_RAW: [Linktype; 2] = [Linktype(12), Linktype(14)];
+
+// ethertypes of 802.1Q tags, 802.1ad and the older one being used for QinQ
+const VLAN_ETHERTYPES: [u16; 3] = [0x8100, 0x88a8, 0x9100];
+
+// address families of the loopback header, IPv6 has one per BSD flavour
+const AF_INET: u32 = 2;
+const AF_INET6: [u32; 4] = [10, 24, 28, 30];
+
+pub struct PacketParse {
+    linktype: Linktype,
+    fragments: FragmentReassembler,
+    // capture time of the packet being parsed
+    time: Duration,
+}
+
+impl Default for PacketParse {
+    fn default() -> PacketParse {
+        PacketParse::new()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub enum PacketHeader {
+    Tls(TlsType),
+    Dns(DnsPacket),
+    Tcp(TcpHeader),
+    Udp(UdpHeader),
+    Ipv4(IPv4Header),
+    Ipv6(IPv6Header),
+    Ether(EthernetFrame),
+    Arp(ArpPacket),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ParsedPacket {
+    pub len: u32,
+    pub timestamp: String,
+    pub headers: Vec<PacketHeader>,
+    pub remaining: Vec<u8>,
+    /// Transport layer payload, as long as the headers declare it.
+    pub payload: Vec<u8>,
+}
+
+impl ParsedPacket {
+    pub fn new() -> ParsedPacket {
+        ParsedPacket {
+            len: 0,
+            timestamp: "".to_string(),
+            headers: vec![],
+            remaining: vec![],
+            payload: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub enum TlsType {
+    Handshake,
+    ChangeCipherSpec,
+    Alert,
+    ApplicationData,
+    Heartbeat,
+    EncryptedData,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct DnsPacket {
+    questions: Vec<String>,
+    answers: Vec<String>,
+}
+
+impl From<dns_parser::Packet<'_>> for DnsPacket {
+    fn from(dns_packet: dns_parser::Packet) -> Self {
+        let questions: Vec<String> = dns_packet
+            .questions
+            .iter()
+            .map(|q| q.qname.to_string())
+            .collect();
+        let answers: Vec<String> = dns_packet
+            .answers
+            .iter()
+            .map(|a| a.name.to_string())
+            .collect();
+        Self { questions, answers }
+    }
+}
+
+impl ToString for PacketHeader {
+    fn to_string(&self) -> String {
+        match self {
+            PacketHeader::Ipv4(_) => String::from("Ipv4"),
+            PacketHeader::Ipv6(_) => String::from("Ipv6"),
+            PacketHeader::Dns(_) => String::from("Dns"),
+            PacketHeader::Tls(_) => String::from("Tls"),
+            PacketHeader::Tcp(_) => String::from("Tcp"),
+            PacketHeader::Udp(_) => String::from("Udp"),
+            PacketHeader::Ether(_) => String::from("Ether"),
+            PacketHeader::Arp(_) => String::from("Arp"),
+        }
+    }
+}
+
+impl PacketParse {
+    pub fn new() -> PacketParse {
+        PacketParse::with_linktype(Linktype::ETHERNET)
+    }
+
+    /// Parser of the frames of a capture with this datalink.
+    pub fn with_linktype(linktype: Linktype) -> PacketParse {
+        PacketParse {
+            linktype,
+            fragments: FragmentReassembler::new(),
+            time: Duration::ZERO,
+        }
+    }
+
+    /// Whether frames of this datalink can be parsed.
+    pub fn supports(linktype: Linktype) -> bool {
+        matches!(
+            linktype,
+            Linktype::ETHERNET
+                | Linktype::LINUX_SLL
+                | Linktype::LINUX_SLL2
+                | Linktype::NULL
+                | Linktype::LOOP
+                | Linktype::RAW
+                | Linktype::IPV4
+                | Linktype::IPV6
+        ) || DLT_RAW.contains(&linktype)
+    }
+
+    pub fn parse_packet(
+        &mut self,
+        data: Vec<u8>,
+        len: u32,
+        time: Duration,
+    ) -> Result<ParsedPacket, String> {
+        self.time = time;
+        let mut parsed_packet = self.parse_link_layer(&data)?;
+        parsed_packet.len = len;
+        parsed_packet.timestamp = format!("{}.{:06}", time.as_secs(), time.subsec_micros());
+        Ok(parsed_packet)
+    }
+
+    pub fn parse_link_layer(&mut self, content: &[u8]) -> Result<ParsedPacket, String> {
+        match self.linktype {
+            Linktype::ETHERNET => self.parse_ethernet(content),
+            // linux cooked capture, used by the `any` device
+            Linktype::LINUX_SLL => self.parse_cooked(content, 16, 14),
+            Linktype::LINUX_SLL2 => self.parse_cooked(content, 20, 0),
+            // BSD loopback, in the byte order of the capturing host for NULL
+            Linktype::NULL => self.parse_loopback(content, false),
+            Linktype::LOOP => self.parse_loopback(content, true),
+            Linktype::RAW | Linktype::IPV4 | Linktype::IPV6 => self.parse_raw(content),
+            linktype if DLT_RAW.contains(&linktype) => self.parse_raw(content),
+            _ => {
+                let mut pack = ParsedPacket::new();
+                pack.remaining = content.to_owned();
+                Ok(pack)
+            }
+        }
+    }
+
+    pub fn parse_ethernet(&mut self, content: &[u8]) -> Result<ParsedPacket, String> {
+        let mut pack = ParsedPacket::new();
+        match ethernet::parse_ethernet_frame(content) {
+            Ok((content, headers)) => {
+                match headers.ethertype {
+                    EtherType::IPv4 => {
+                        self.parse_ipv4(content, &mut pack)?;
+                    }
+                    EtherType::IPv6 => {
+                        self.parse_ipv6(content, &mut pack)?;
+                    }
+                    EtherType::ARP => {
+                        self.parse_arp(content, &mut pack)?;
+                    }
+                    EtherType::VLAN | EtherType::QinQ | EtherType::VLANdouble => {
+                        self.parse_vlan(content, &mut pack)?;
+                    }
+                    _ => {
+                        pack.remaining = content.to_owned();
+                    }
+                }
+                pack.headers.push(PacketHeader::Ether(headers));
+            }
+            Err(_) => {
+                pack.remaining = content.to_owned();
+            }
+        }
+        Ok(pack)
+    }
+
+    fn parse_cooked(
+        &mut self,
+        content: &[u8],
+        header_len: usize,
+        protocol_at: usize,
+    ) -> Result<ParsedPacket, String> {
+        let mut pack = ParsedPacket::new();
+        match (
+            content.get(protocol_at..protocol_at + 2),
+            content.get(header_len..),
+        ) {
+            (Some(protocol), Some(content)) => {
+                let protocol = u16::from_be_bytes([protocol[0], protocol[1]]);
+                self.parse_ethertype(protocol, content, &mut pack)?;
+            }
+            _ => pack.remaining = content.to_owned(),
+        }
+        Ok(pack)
+    }
+
+    fn parse_loopback(&mut self, content: &[u8], big_endian: bool) -> Result<ParsedPacket, String> {
+        let mut pack = ParsedPacket::new();
+        let (family, content) = match content.get(..4) {
+            Some(family) => ([family[0], family[1], family[2], family[3]], &content[4..]),
+            None => {
+                pack.remaining = content.to_owned();
+                return Ok(pack);
+            }
+        };
+
+        // the family is a small number, only its low bytes are set
+        let family = match (big_endian, u32::from_le_bytes(family)) {
+            (false, family) if family <= 0xffff => family,
+            _ => u32::from_be_bytes(family),
+        };
+
+        if family == AF_INET {
+            self.parse_ipv4(content, &mut pack)?;
+        } else if AF_INET6.contains(&family) {
+            self.parse_ipv6(content, &mut pack)?;
+        } else {
+            pack.remaining = content.to_owned();
+        }
+        Ok(pack)
+    }
+
+    // bare IP packets, the version tells which
+    fn parse_raw(&mut self, content: &[u8]) -> Result<ParsedPacket, String> {
+        let mut pack = ParsedPacket::new();
+        match content.first().map(|byte| byte >> 4) {
+            Some(4) => self.parse_ipv4(content, &mut pack)?,
+            Some(6) => self.parse_ipv6(content, &mut pack)?,
+            _ => pack.remaining = content.to_owned(),
+        }
+        Ok(pack)
+    }
+
+    // each tag is the tag control then the ethertype of what follows, stacked for QinQ
+    fn parse_vlan(
+        &mut self,
+        content: &[u8],
+        parsed_packet: &mut ParsedPacket,
+    ) -> Result<(), String> {
+        let mut content = content;
+        loop {
+            let ethertype = match content.get(2..4) {
+                Some(ethertype) => u16::from_be_bytes([ethertype[0], ethertype[1]]),
+                None => {
+                    parsed_packet.remaining = content.to_owned();
+                    return Ok(());
+                }
+            };
+            content = &content[4..];
+
+            if !VLAN_ETHERTYPES.contains(&ethertype) {
+                return self.parse_ethertype(ethertype, content, parsed_packet);
+            }
+        }
+    }
+
+    // the network layer named by an ethertype, as found in cooked headers and VLAN tags
+    fn parse_ethertype(
+        &mut self,
+        ethertype: u16,
+        content: &[u8],
+        parsed_packet: &mut ParsedPacket,
+    ) -> Result<(), String> {
+        match ethertype {
+            0x0800 => self.parse_ipv4(content, parsed_packet),
+            0x86dd => self.parse_ipv6(content, parsed_packet),
+            0x0806 => self.parse_arp(content, parsed_packet),
+            ethertype if VLAN_ETHERTYPES.contains(&ethertype) => {
+                self.parse_vlan(content, parsed_packet)
+            }
+            _ => {
+                parsed_packet.remaining = content.to_owned();
+                Ok(())
+            }
+        }
+    }
+
+    pub fn parse_ipv4(
+        &mut self,
+        content: &[u8],
+        parsed_packet: &mut ParsedPacket,
+    ) -> Result<(), String> {
+        match ipv4::parse_ipv4_header(content) {
+            Ok((_, headers)) => {
+                // `ihl` is in bytes, the parser doesn't skip the options
+                // and frames below the ethernet minimum are padded past the packet
+                let header_len = usize::from(headers.ihl);
+                let total_len = min(usize::from(headers.length), content.len());
+                let protocol = content[9];
+                let content = content
+                    .get(header_len.max(20)..total_len)
+                    .ok_or_else(|| format!("Invalid IPv4 lengths {}/{}", header_len, total_len))?;
+
+                if FragmentReassembler::is_fragment(&headers) {
+                    // the transport header is only in the first fragment, wait for them all
+                    match self.fragments.push(&headers, protocol, content, self.time) {
+                        Some(datagram) => {
+                            self.parse_transport_layer(&headers.protocol, &datagram, parsed_packet)?
+                        }
+                        None => parsed_packet.remaining = content.to_owned(),
+                    }
+                } else {
+                    self.parse_transport_layer(&headers.protocol, content, parsed_packet)?;
+                }
+                parsed_packet.headers.push(PacketHeader::Ipv4(headers));
+                Ok(())
+            }
+            Err(err) => {
+                parsed_packet.remaining = content.to_owned();
+                Err(err.to_string())
+            }
+        }
+    }
+
+    pub fn parse_ipv6(
+        &self,
+        content: &[u8],
+        parsed_packet: &mut ParsedPacket,
+    ) -> Result<(), String> {
+        match ipv6::parse_ipv6_header(content) {
+            Ok((payload, headers)) => {
+                let payload = &payload[..min(usize::from(headers.length), payload.len())];
+                // the parsed next header may be an extension one, walk them from the raw value
+                match ipv6_upper_layer(content[6], payload) {
+                    Some((protocol, payload)) => {
+                        let protocol = ip::to_ip_protocol(protocol);
+                        self.parse_transport_layer(&protocol, payload, parsed_packet)?;
+                    }
+                    None => parsed_packet.remaining = payload.to_owned(),
+                }
+                parsed_packet.headers.push(PacketHeader::Ipv6(headers));
+                Ok(())
+            }
+            Err(err) => {
+                parsed_packet.remaining = content.to_owned();
+                Err(err.to_string())
+            }
+        }
+    }
+
+    fn parse_transport_layer(
+        &self,
+        protocol: &ip::IPProtocol,
+        content: &[u8],
+        parsed_packet: &mut ParsedPacket,
+    ) -> Result<(), String> {
+        match protocol {
+            IPProtocol::UDP => {
+                self.parse_udp(content, parsed_packet)?;
+                Ok(())
+            }
+            IPProtocol::TCP => {
+                self.parse_tcp(content, parsed_packet)?;
+                Ok(())
+            }
+            _ => {
+                parsed_packet.remaining = content.to_owned();
+                Err("Neither TCP nor UDP".to_string())
+            }
+        }
+    }
+
+    fn parse_tcp(&self, content: &[u8], parsed_packet: &mut ParsedPacket) -> Result<(), String> {
+        match tcp::parse_tcp_header(content) {
+            Ok((_, headers)) => {
+                // options take data_offset words, whether the parser understood them or not
+                let header_len = usize::from(headers.data_offset) * 4;
+                let content = content
+                    .get(header_len.max(20)..)
+                    .ok_or_else(|| format!("Invalid TCP data offset {}", headers.data_offset))?;
+                parsed_packet.payload = content.to_owned();
+                self.parse_tls(content, parsed_packet);
+                parsed_packet.headers.push(PacketHeader::Tcp(headers));
+                Ok(())
+            }
+            Err(err) => {
+                parsed_packet.remaining = content.to_owned();
+                Err(err.to_string())
+            }
+        }
+    }
+
+    fn parse_udp(&self, content: &[u8], parsed_packet: &mut ParsedPacket) -> Result<(), String> {
+        match udp::parse_udp_header(content) {
+            Ok((content, headers)) => {
+                let len = usize::from(headers.length).saturating_sub(8);
+                let content = &content[..min(len, content.len())];
+                parsed_packet.payload = content.to_owned();
+                self.parse_dns(content, parsed_packet);
+                parsed_packet.headers.push(PacketHeader::Udp(headers));
+                Ok(())
+            }
+            Err(err) => {
+                parsed_packet.remaining = content.to_owned();
+                Err(err.to_string())
+            }
+        }
+    }
+
+    fn parse_arp(&self, content: &[u8], parsed_packet: &mut ParsedPacket) -> Result<(), String> {
+        match arp::parse_arp_pkt(content) {
+            Ok((_content, headers)) => {
+                parsed_packet.headers.push(PacketHeader::Arp(headers));
+                Ok(())
+            }
+            Err(err) => {
+                parsed_packet.remaining = content.to_owned();
+                Err(err.to_string())
+            }
+        }
+    }
+
+    fn parse_dns(&self, content: &[u8], parsed_packet: &mut ParsedPacket) {
+        match dns_parser::Packet::parse(content) {
+            Ok(packet) => {
+                parsed_packet
+                    .headers
+                    .push(PacketHeader::Dns(DnsPacket::from(packet)));
+            }
+            Err(_) => {
+                parsed_packet.remaining = content.to_owned();
+            }
+        }
+    }
+
+    fn parse_tls(&self, content: &[u8], parsed_packet: &mut ParsedPacket) {
+        if let Ok((_content, headers)) = tls_parser::parse_tls_plaintext(content) {
+            if let Some(msg) = headers.msg.get(0) {
+                match msg {
+                    TlsMessage::Handshake(_) => {
+                        parsed_packet
+                            .headers
+                            .push(PacketHeader::Tls(TlsType::Handshake));
+                    }
+                    TlsMessage::ApplicationData(app_data) => {
+                        parsed_packet
+                            .headers
+                            .push(PacketHeader::Tls(TlsType::ApplicationData));
+                        parsed_packet.remaining = app_data.blob.to_owned();
+                    }
+                    TlsMessage::Heartbeat(_) => {
+                        parsed_packet
+                            .headers
+                            .push(PacketHeader::Tls(TlsType::Heartbeat));
+                    }
+                    TlsMessage::ChangeCipherSpec => {
+                        parsed_packet
+                            .headers
+                            .push(PacketHeader::Tls(TlsType::ChangeCipherSpec));
+                    }
+                    TlsMessage::Alert(_) => {
+                        parsed_packet
+                            .headers
+                            .push(PacketHeader::Tls(TlsType::Alert));
+                    }
+                }
+            }
+        } else if let Ok((_content, headers)) = tls_parser::parse_tls_encrypted(content) {
+            parsed_packet
+                .headers
+                .push(PacketHeader::Tls(TlsType::EncryptedData));
+            parsed_packet.remaining = headers.msg.blob.to_owned();
+        } else {
+            parsed_packet.remaining = content.to_owned();
+        }
+    }
+}
+
+// skip the IPv6 extension headers up to the upper layer protocol,
+// `None` when it can't be reached: truncated, or a fragment of a bigger packet
+fn ipv6_upper_layer(next_header: u8, content: &[u8]) -> Option<(u8, &[u8])> {
+    let (mut next_header, mut content) = (next_header, content);
+    loop {
+        let len = match next_header {
+            // hop-by-hop, routing, destination options, mobility, HIP and shim6 count 8 bytes units
+            0 | 43 | 60 | 135 | 139 | 140 => (usize::from(*content.get(1)?) + 1) * 8,
+            // fragment, fine when it's the whole packet: no offset nor more fragments
+            44 => {
+                let offset = u16::from_be_bytes([*content.get(2)?, *content.get(3)?]);
+                if offset & 0xfff9 != 0 {
+                    return None;
+                }
+                8
+            }
+            // authentication header counts 4 bytes units
+            51 => (usize::from(*content.get(1)?) + 2) * 4,
+            _ => return Some((next_header, content)),
+        };
+
+        next_header = *content.first()?;
+        content = content.get(len..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: &[u8] = b"xyz";
+
+    // TCP segment from 5555 to 50000, `options` bytes of timestamps padded with NOPs
+    fn tcp(payload: &[u8], options: usize) -> Vec<u8> {
+        let data_offset = ((20 + options) / 4) as u8;
+        let mut segment = vec![0x15, 0xb3, 0xc3, 0x50, 0, 0, 0, 1, 0, 0, 0, 0];
+        segment.extend_from_slice(&[data_offset << 4, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        let mut timestamps = vec![1, 1, 8, 10, 0, 0, 0, 1, 0, 0, 0, 2];
+        timestamps.resize(options, 1);
+        segment.extend(timestamps);
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    // IPv4 packet from 10.0.0.1 to 10.0.0.2, `options` bytes of NOPs
+    fn ipv4(segment: &[u8], options: usize) -> Vec<u8> {
+        let header_len = 20 + options;
+        let total = (header_len + segment.len()) as u16;
+        let mut packet = vec![0x40 | (header_len / 4) as u8, 0];
+        packet.extend_from_slice(&total.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend(vec![1; options]);
+        packet.extend_from_slice(segment);
+        packet
+    }
+
+    // IPv6 packet from ::1 to ::2, `next` naming the first of the `extensions`
+    fn ipv6(next: u8, extensions: &[u8], segment: &[u8]) -> Vec<u8> {
+        let len = (extensions.len() + segment.len()) as u16;
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.extend_from_slice(&[next, 64]);
+        for last in [1, 2] {
+            packet.extend_from_slice(&[0; 15]);
+            packet.push(last);
+        }
+        packet.extend_from_slice(extensions);
+        packet.extend_from_slice(segment);
+        packet
+    }
+
+    fn ethernet(ethertype: u16, packet: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    fn parse(linktype: Linktype, frame: &[u8]) -> Result<ParsedPacket, String> {
+        PacketParse::with_linktype(linktype).parse_packet(
+            frame.to_owned(),
+            frame.len() as u32,
+            Duration::ZERO,
+        )
+    }
+
+    fn payload(linktype: Linktype, frame: &[u8]) -> Vec<u8> {
+        parse(linktype, frame).unwrap().payload
+    }
+
+    #[test]
+    fn slices_tcp_payload_after_options() {
+        for (ip_options, tcp_options) in [(0, 0), (0, 12), (4, 12), (8, 20)] {
+            let frame = ethernet(0x0800, &ipv4(&tcp(PAYLOAD, tcp_options), ip_options));
+            assert_eq!(payload(Linktype::ETHERNET, &frame), PAYLOAD);
+        }
+
+        // ethernet pads short frames past the end of the IP packet
+        let mut frame = ethernet(0x0800, &ipv4(&tcp(PAYLOAD, 0), 0));
+        frame.extend_from_slice(&[0xee; 10]);
+        assert_eq!(payload(Linktype::ETHERNET, &frame), PAYLOAD);
+    }
+
+    fn link_frames() -> Vec<(&'static str, Linktype, Vec<u8>)> {
+        let segment = tcp(PAYLOAD, 12);
+        let v4 = ipv4(&segment, 0);
+        let v6 = ipv6(6, &[], &segment);
+        let sll = [0, 0, 0, 1, 0, 6, 1, 2, 3, 4, 5, 6, 0, 0];
+        let sll2 = [0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 6, 1, 2, 3, 4, 5, 6, 0, 0];
+
+        vec![
+            ("ethernet v4", Linktype::ETHERNET, ethernet(0x0800, &v4)),
+            ("ethernet v6", Linktype::ETHERNET, ethernet(0x86dd, &v6)),
+            (
+                "sll v4",
+                Linktype::LINUX_SLL,
+                [&sll[..], &[0x08, 0x00], &v4].concat(),
+            ),
+            (
+                "sll v6",
+                Linktype::LINUX_SLL,
+                [&sll[..], &[0x86, 0xdd], &v6].concat(),
+            ),
+            (
+                "sll2 v4",
+                Linktype::LINUX_SLL2,
+                [&[0x08, 0x00], &sll2[2..], &v4].concat(),
+            ),
+            (
+                "sll2 v6",
+                Linktype::LINUX_SLL2,
+                [&[0x86, 0xdd], &sll2[2..], &v6].concat(),
+            ),
+            (
+                "null le v4",
+                Linktype::NULL,
+                [&[2, 0, 0, 0][..], &v4].concat(),
+            ),
+            (
+                "null be v4",
+                Linktype::NULL,
+                [&[0, 0, 0, 2][..], &v4].concat(),
+            ),
+            (
+                "null le v6",
+                Linktype::NULL,
+                [&[30, 0, 0, 0][..], &v6].concat(),
+            ),
+            (
+                "null be v6",
+                Linktype::NULL,
+                [&[0, 0, 0, 24][..], &v6].concat(),
+            ),
+            ("loop v4", Linktype::LOOP, [&[0, 0, 0, 2][..], &v4].concat()),
+            (
+                "loop v6",
+                Linktype::LOOP,
+                [&[0, 0, 0, 28][..], &v6].concat(),
+            ),
+            ("raw v4", Linktype::RAW, v4.clone()),
+            ("raw v6", Linktype::RAW, v6.clone()),
+            ("dlt raw", Linktype(12), v6),
+            ("ipv4", Linktype::IPV4, v4),
+        ]
+    }
+
+    #[test]
+    fn parses_every_link_type() {
+        for (name, linktype, frame) in link_frames() {
+            assert!(PacketParse::supports(linktype), "{}", name);
+            assert_eq!(payload(linktype, &frame), PAYLOAD, "{}", name);
+        }
+
+        // unknown families and link types are left alone
+        let frame = [&[0, 0, 0, 7][..], &ipv4(&tcp(PAYLOAD, 0), 0)].concat();
+        assert!(payload(Linktype::LOOP, &frame).is_empty());
+        assert!(!PacketParse::supports(Linktype(147)));
+        assert!(payload(Linktype(147), &frame).is_empty());
+    }
+
+    #[test]
+    fn survives_truncated_frames() {
+        for (name, linktype, frame) in link_frames() {
+            for len in 0..frame.len() {
+                // a capture cut in the payload still gives what it has
+                if let Ok(parsed) = parse(linktype, &frame[..len]) {
+                    assert!(PAYLOAD.starts_with(&parsed.payload), "{} at {}", name, len);
+                }
+            }
+        }
+    }
+
+    // ethernet frame with stacked tags, each followed by the ethertype of what comes next
+    fn vlan(tags: &[u16], ethertype: u16, packet: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&tags[0].to_be_bytes());
+        for next in tags[1..].iter().chain(std::iter::once(&ethertype)) {
+            frame.extend_from_slice(&[0, 42]);
+            frame.extend_from_slice(&next.to_be_bytes());
+        }
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    #[test]
+    fn walks_vlan_tags() {
+        let v4 = ipv4(&tcp(PAYLOAD, 12), 0);
+        let v6 = ipv6(6, &[], &tcp(PAYLOAD, 12));
+
+        let frames = [
+            vlan(&[0x8100], 0x0800, &v4),
+            vlan(&[0x88a8, 0x8100], 0x0800, &v4),
+            vlan(&[0x9100, 0x8100, 0x8100], 0x86dd, &v6),
+        ];
+        for frame in frames.iter() {
+            assert_eq!(payload(Linktype::ETHERNET, frame), PAYLOAD);
+        }
+
+        // tags of a cooked capture
+        let sll = [
+            &[0, 0, 0, 1, 0, 6, 1, 2, 3, 4, 5, 6, 0, 0, 0x81, 0][..],
+            &[0, 42, 0x08, 0],
+            &v4,
+        ];
+        assert_eq!(payload(Linktype::LINUX_SLL, &sll.concat()), PAYLOAD);
+
+        let truncated = vlan(&[0x8100, 0x8100], 0x0800, &[]);
+        for len in 14..truncated.len() {
+            assert!(payload(Linktype::ETHERNET, &truncated[..len]).is_empty());
+        }
+    }
+
+    #[test]
+    fn walks_ipv6_extension_headers() {
+        let segment = tcp(PAYLOAD, 12);
+        let frame = |next, extensions: &[u8]| ethernet(0x86dd, &ipv6(next, extensions, &segment));
+
+        // hop-by-hop of 8 bytes then destination options of 16
+        let mut options = vec![60, 0, 1, 4, 0, 0, 0, 0];
+        options.extend_from_slice(&[6, 1, 1, 4, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(payload(Linktype::ETHERNET, &frame(0, &options)), PAYLOAD);
+
+        // routing header then a fragment header covering the whole packet
+        let routing = [44, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 9];
+        assert_eq!(payload(Linktype::ETHERNET, &frame(43, &routing)), PAYLOAD);
+
+        // authentication header of 12 bytes, counted in 4 bytes units minus 2
+        let ah = [6, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2];
+        assert_eq!(payload(Linktype::ETHERNET, &frame(51, &ah)), PAYLOAD);
+        let ah_then_options = [60, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 6, 0, 1, 4, 0, 0, 0, 0];
+        assert_eq!(
+            payload(Linktype::ETHERNET, &frame(51, &ah_then_options)),
+            PAYLOAD
+        );
+
+        // the first fragment of a bigger packet, or a later one, has no segment to give
+        let first = [6, 0, 0, 1, 0, 0, 0, 9];
+        assert!(payload(Linktype::ETHERNET, &frame(44, &first)).is_empty());
+        let later = [6, 0, 0, 0x10, 0, 0, 0, 9];
+        assert!(payload(Linktype::ETHERNET, &frame(44, &later)).is_empty());
+
+        // extension headers running past the packet
+        let options = [6, 8, 0, 0, 0, 0, 0, 0];
+        let truncated = ipv6(0, &options, &[]);
+        assert!(payload(Linktype::ETHERNET, &ethernet(0x86dd, &truncated)).is_empty());
+        let truncated = ipv6(0, &options[..4], &[]);
+        assert!(payload(Linktype::ETHERNET, &ethernet(0x86dd, &truncated)).is_empty());
+    }
+}
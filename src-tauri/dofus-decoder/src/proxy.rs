@@ -0,0 +1,543 @@
+use crate::{
+    packet_decoder::{decode_body, DofusPacket, PacketDecoder, Source},
+    packet_encoder::encode_message,
+    schema::{Schema, TypeDef},
+    tcp_reassembly::FlowKey,
+    value::DecodedValue,
+};
+use log::{info, warn};
+use std::{
+    convert::TryInto,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// How long the listener waits for a client before checking if it was stopped
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+// How long the relay of a game server waits for the client handed over to it
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Messages sending the client to a game server, rewritten to go through the proxy
+const HANDOVER_MESSAGES: [&str; 2] = [
+    "SelectedServerDataMessage",
+    "SelectedServerDataExtendedMessage",
+];
+
+/// Called with the messages decoded from each chunk relayed.
+pub type MessageHandler = dyn Fn(Vec<DofusPacket>) + Send + Sync;
+
+/// Relays a local port to a Dofus server and decodes the traffic going through.
+///
+/// The game connects to the local port instead of the server: no capture
+/// privileges are needed, and the streams come already ordered. The game
+/// server the client is handed over to after login is relayed as well, on
+/// a port opened next to the local one.
+pub struct Proxy {
+    listener: TcpListener,
+    upstream: SocketAddr,
+}
+
+impl Proxy {
+    pub fn bind<A: ToSocketAddrs>(addr: A, upstream: SocketAddr) -> io::Result<Proxy> {
+        Ok(Proxy {
+            listener: TcpListener::bind(addr)?,
+            upstream,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn upstream(&self) -> SocketAddr {
+        self.upstream
+    }
+
+    /// Relay every client connecting, each on its own threads, until `stop` is set
+    /// or the listener fails. Connections already relayed go on until they close.
+    pub fn run(
+        &self,
+        decoder: Arc<Mutex<PacketDecoder>>,
+        on_messages: Arc<MessageHandler>,
+        stop: &AtomicBool,
+    ) -> io::Result<()> {
+        let stopped = || stop.load(Ordering::Relaxed);
+
+        while let Some((client, client_addr)) = accept(&self.listener, &stopped)? {
+            let upstream = self.upstream;
+            let decoder = decoder.clone();
+            let on_messages = on_messages.clone();
+
+            thread::spawn(move || {
+                if let Err(err) = relay(client, client_addr, upstream, decoder, on_messages) {
+                    warn!("Connection of {} interrupted: {}", client_addr, err);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+// next client of `listener`, `None` once `give_up` says so
+fn accept(
+    listener: &TcpListener,
+    give_up: &dyn Fn() -> bool,
+) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+    listener.set_nonblocking(true)?;
+
+    while !give_up() {
+        match listener.accept() {
+            Ok((client, client_addr)) => {
+                // some platforms hand out the client with the mode of the listener
+                client.set_nonblocking(false)?;
+                return Ok(Some((client, client_addr)));
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(None)
+}
+
+fn relay(
+    client: TcpStream,
+    client_addr: SocketAddr,
+    upstream: SocketAddr,
+    decoder: Arc<Mutex<PacketDecoder>>,
+    on_messages: Arc<MessageHandler>,
+) -> io::Result<()> {
+    let server = TcpStream::connect(upstream)?;
+    info!("Relaying {} to {}", client_addr, upstream);
+
+    let flow = FlowKey {
+        src_addr: client_addr.ip(),
+        src_port: client_addr.port(),
+        dst_addr: upstream.ip(),
+        dst_port: upstream.port(),
+    };
+
+    let to_server = {
+        let (client, server) = (client.try_clone()?, server.try_clone()?);
+        let (decoder, on_messages) = (decoder.clone(), on_messages.clone());
+        thread::spawn(move || pump(client, server, flow, Source::Client, &decoder, &on_messages))
+    };
+    let to_client = pump(
+        server,
+        client,
+        flow.reverse(),
+        Source::Server,
+        &decoder,
+        &on_messages,
+    );
+
+    let to_server = to_server
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "relay panicked")));
+    decoder
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .close(&flow);
+    info!("Connection of {} closed", client_addr);
+    to_client.and(to_server)
+}
+
+// forward one direction until it is closed, decoding what goes through
+fn pump(
+    mut from: TcpStream,
+    mut to: TcpStream,
+    flow: FlowKey,
+    source: Source,
+    decoder: &Arc<Mutex<PacketDecoder>>,
+    on_messages: &Arc<MessageHandler>,
+) -> io::Result<()> {
+    let mut buf = vec![0; 65536];
+    // incomplete server messages are held back, they may hand the client over
+    let mut held = vec![];
+
+    let result = loop {
+        let len = match from.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => break Err(err),
+        };
+
+        // forward first, decoding must not delay the game
+        let forwarded = match source {
+            Source::Client => to.write_all(&buf[..len]),
+            Source::Server => {
+                held.extend_from_slice(&buf[..len]);
+                let complete: Vec<u8> = held.drain(..complete_len(&held)).collect();
+                let messages = hand_over(&complete, &to, flow, decoder, on_messages);
+                to.write_all(&messages)
+            }
+        };
+        if let Err(err) = forwarded {
+            break Err(err);
+        }
+
+        let messages = {
+            let mut decoder = decoder.lock().unwrap_or_else(PoisonError::into_inner);
+            decoder.decode_packet(flow, &buf[..len], source, now());
+            decoder.get_messages()
+        };
+        if !messages.is_empty() {
+            on_messages(messages);
+        }
+    };
+
+    // let the other side see the end of the stream, or unblock its relay on errors
+    let _ = to.shutdown(Shutdown::Write);
+    if result.is_err() {
+        let _ = from.shutdown(Shutdown::Both);
+    }
+    decoder
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .reset(&flow);
+
+    result
+}
+
+// bytes of the server messages fully held in `bytes`
+fn complete_len(bytes: &[u8]) -> usize {
+    let mut complete = 0;
+    while let Some(len) = message_len(&bytes[complete..]) {
+        complete += len;
+    }
+    complete
+}
+
+// size of the server message starting `bytes`, header included, if all there
+fn message_len(bytes: &[u8]) -> Option<usize> {
+    let hi_header = u16::from_be_bytes(bytes.get(..2)?.try_into().ok()?);
+    let length_type = usize::from(hi_header & 3);
+    let length = bytes
+        .get(2..2 + length_type)?
+        .iter()
+        .fold(0, |length, byte| length << 8 | usize::from(*byte));
+
+    let len = 2 + length_type + length;
+    if bytes.len() >= len {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+// server messages as they are forwarded, the game servers announced replaced by relays
+fn hand_over(
+    messages: &[u8],
+    client: &TcpStream,
+    flow: FlowKey,
+    decoder: &Arc<Mutex<PacketDecoder>>,
+    on_messages: &Arc<MessageHandler>,
+) -> Vec<u8> {
+    let schema = decoder
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .schema(&flow);
+    let mut forwarded = Vec::with_capacity(messages.len());
+
+    let mut rest = messages;
+    while let Some(len) = message_len(rest) {
+        let (message, next) = rest.split_at(len);
+        rest = next;
+
+        let id = u16::from_be_bytes([message[0], message[1]]) >> 2;
+        let handover = schema
+            .message(id)
+            .filter(|type_def| HANDOVER_MESSAGES.contains(&type_def.name.as_str()));
+        let redirected = handover.map(|type_def| {
+            let payload = &message[2 + usize::from(message[1] & 3)..];
+            redirect(&schema, type_def, payload, client, decoder, on_messages)
+        });
+
+        match redirected {
+            Some(Ok(redirected)) => forwarded.extend(redirected),
+            Some(Err(err)) => {
+                warn!(
+                    "Unable to relay the game server, the client will bypass the proxy: {}",
+                    err
+                );
+                forwarded.extend_from_slice(message);
+            }
+            None => forwarded.extend_from_slice(message),
+        }
+    }
+
+    forwarded
+}
+
+// relay the game server announced in `payload` on a new port, returns the
+// message pointing the client there instead
+fn redirect(
+    schema: &Schema,
+    message: &TypeDef,
+    payload: &[u8],
+    client: &TcpStream,
+    decoder: &Arc<Mutex<PacketDecoder>>,
+    on_messages: &Arc<MessageHandler>,
+) -> io::Result<Vec<u8>> {
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+
+    let mut body = decode_body(schema, message, payload).map_err(|err| invalid(err.to_string()))?;
+    let address = body
+        .get("address")
+        .and_then(DecodedValue::as_str)
+        .ok_or_else(|| invalid(String::from("no address")))?;
+    // sent as VarShort, ports above 32767 come out negative
+    let port = body
+        .get("ports")
+        .and_then(DecodedValue::as_array)
+        .and_then(|ports| ports.first())
+        .and_then(DecodedValue::as_i64)
+        .ok_or_else(|| invalid(String::from("no port")))? as u16;
+    let upstream = (address, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| invalid(format!("{} not found", address)))?;
+
+    // the client reaches the relay the way it reached the proxy
+    let listener = TcpListener::bind((client.local_addr()?.ip(), 0))?;
+    let local = listener.local_addr()?;
+    info!("Relaying game server {} on {}", upstream, local);
+
+    if let Some(address) = body.get_mut("address") {
+        *address = DecodedValue::Utf(local.ip().to_string());
+    }
+    if let Some(ports) = body.get_mut("ports") {
+        *ports = DecodedValue::Array(vec![DecodedValue::VarShort(local.port() as i16)]);
+    }
+    let redirected =
+        encode_message(schema, message, &body, None).map_err(|err| invalid(err.to_string()))?;
+
+    let (decoder, on_messages) = (decoder.clone(), on_messages.clone());
+    thread::spawn(move || {
+        let deadline = Instant::now() + HANDOVER_TIMEOUT;
+        let result =
+            accept(&listener, &|| Instant::now() > deadline).and_then(|client| match client {
+                Some((client, client_addr)) => {
+                    relay(client, client_addr, upstream, decoder, on_messages)
+                }
+                None => {
+                    warn!("The client never came to the relay of {}", upstream);
+                    Ok(())
+                }
+            });
+        if let Err(err) = result {
+            warn!("Relay of game server {} interrupted: {}", upstream, err);
+        }
+    });
+
+    Ok(redirected)
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_message, DecodedValue, Schema, Struct};
+    use std::sync::mpsc;
+
+    fn message(schema: &Schema, name: &str, body: Struct, instance_id: Option<u32>) -> Vec<u8> {
+        let message = schema.message_by_name(name).unwrap();
+        encode_message(schema, message, &body, instance_id).unwrap()
+    }
+
+    #[test]
+    fn relays_and_decodes_both_directions() {
+        let schema = Schema::embedded().unwrap();
+        let mut hello = Struct::new("HelloConnectMessage");
+        hello.push("salt", 0..0, DecodedValue::Utf(String::from("salt")));
+        hello.push(
+            "key",
+            0..0,
+            DecodedValue::Array(vec![DecodedValue::Byte(-3); 300]),
+        );
+        let hello = message(&schema, "HelloConnectMessage", hello, None);
+        let mut ping = Struct::new("BasicPingMessage");
+        ping.push("quiet", 0..0, DecodedValue::Boolean(true));
+        let ping = message(&schema, "BasicPingMessage", ping, Some(7));
+
+        // stand-in for the game server, sending its message in two segments
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = server.local_addr().unwrap();
+        let server = {
+            let (hello, ping) = (hello.clone(), ping.clone());
+            thread::spawn(move || {
+                let (mut stream, _) = server.accept().unwrap();
+                stream.write_all(&hello[..5]).unwrap();
+                thread::sleep(Duration::from_millis(50));
+                stream.write_all(&hello[5..]).unwrap();
+
+                let mut received = vec![0; ping.len()];
+                stream.read_exact(&mut received).unwrap();
+                received
+            })
+        };
+
+        let proxy = Proxy::bind("127.0.0.1:0", upstream).unwrap();
+        let local = proxy.local_addr().unwrap();
+        let decoder = Arc::new(Mutex::new(PacketDecoder::new().unwrap()));
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let on_messages = move |messages| sender.lock().unwrap().send(messages).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let running = {
+            let stop = stop.clone();
+            thread::spawn(move || proxy.run(decoder, Arc::new(on_messages), &stop))
+        };
+
+        let mut client = TcpStream::connect(local).unwrap();
+        let mut received = vec![0; hello.len()];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, hello);
+        client.write_all(&ping).unwrap();
+        assert_eq!(server.join().unwrap(), ping);
+
+        // the end of the server stream reaches the client
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+
+        // each direction decodes on its own thread, in no particular order
+        let mut messages: Vec<DofusPacket> = (0..2)
+            .flat_map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        messages.sort_by_key(|message| message.source() == Source::Client);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].source(), Source::Server);
+        assert_eq!(messages[0].name(), "HelloConnectMessage");
+        assert_eq!(
+            messages[0]
+                .body()
+                .get("salt")
+                .and_then(DecodedValue::as_str),
+            Some("salt")
+        );
+        assert_eq!(messages[1].source(), Source::Client);
+        assert_eq!(messages[1].name(), "BasicPingMessage");
+        assert_eq!(messages[1].header().instance_id, Some(7));
+
+        stop.store(true, Ordering::Relaxed);
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn follows_the_client_to_its_game_server() {
+        let schema = Schema::embedded().unwrap();
+        let game = TcpListener::bind("127.0.0.1:0").unwrap();
+        let game_addr = game.local_addr().unwrap();
+
+        let mut selected = Struct::new("SelectedServerDataMessage");
+        selected.push("serverId", 0..0, DecodedValue::VarUhShort(11));
+        selected.push(
+            "address",
+            0..0,
+            DecodedValue::Utf(String::from("127.0.0.1")),
+        );
+        selected.push(
+            "ports",
+            0..0,
+            DecodedValue::Array(vec![DecodedValue::VarShort(game_addr.port() as i16)]),
+        );
+        selected.push("canCreateNewCharacter", 0..0, DecodedValue::Boolean(true));
+        selected.push(
+            "ticket",
+            0..0,
+            DecodedValue::Array(vec![DecodedValue::Byte(5); 32]),
+        );
+        let selected = message(&schema, "SelectedServerDataMessage", selected, None);
+        let hello_game = Struct::new("HelloGameMessage");
+        let hello_game = message(&schema, "HelloGameMessage", hello_game, None);
+
+        // stand-ins for the login server, handing over in two segments, and the game server
+        let login = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = login.local_addr().unwrap();
+        let login = {
+            let selected = selected.clone();
+            thread::spawn(move || {
+                let (mut stream, _) = login.accept().unwrap();
+                stream.write_all(&selected[..4]).unwrap();
+                thread::sleep(Duration::from_millis(50));
+                stream.write_all(&selected[4..]).unwrap();
+            })
+        };
+        let game = {
+            let hello_game = hello_game.clone();
+            thread::spawn(move || {
+                let (mut stream, _) = game.accept().unwrap();
+                stream.write_all(&hello_game).unwrap();
+            })
+        };
+
+        let proxy = Proxy::bind("127.0.0.1:0", upstream).unwrap();
+        let decoder = Arc::new(Mutex::new(PacketDecoder::new().unwrap()));
+        let local = proxy.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let on_messages = move |messages| sender.lock().unwrap().send(messages).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let running = {
+            let stop = stop.clone();
+            thread::spawn(move || proxy.run(decoder, Arc::new(on_messages), &stop))
+        };
+
+        // the client is sent to a relay instead of the game server
+        let mut client = TcpStream::connect(local).unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).unwrap();
+        login.join().unwrap();
+        assert_ne!(received, selected);
+
+        let message_def = schema.message_by_name("SelectedServerDataMessage").unwrap();
+        let body = decode_body(&schema, message_def, &received[3..]).unwrap();
+        assert_eq!(
+            body.get("address").and_then(DecodedValue::as_str),
+            Some("127.0.0.1")
+        );
+        let ports = body.get("ports").and_then(DecodedValue::as_array).unwrap();
+        let relay_port = ports[0].as_i64().unwrap() as u16;
+        assert_ne!(relay_port, game_addr.port());
+        assert_eq!(
+            body.get("ticket"),
+            Some(&DecodedValue::Array(vec![DecodedValue::Byte(5); 32]))
+        );
+
+        let mut client = TcpStream::connect(("127.0.0.1", relay_port)).unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).unwrap();
+        game.join().unwrap();
+        assert_eq!(received, hello_game);
+
+        // the messages decoded are the ones the servers sent
+        let messages: Vec<DofusPacket> = (0..2)
+            .flat_map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].name(), "SelectedServerDataMessage");
+        let ports = messages[0].body().get("ports");
+        let game_port = DecodedValue::VarShort(game_addr.port() as i16);
+        assert_eq!(ports, Some(&DecodedValue::Array(vec![game_port])));
+        assert_eq!(messages[1].name(), "HelloGameMessage");
+
+        stop.store(true, Ordering::Relaxed);
+        running.join().unwrap().unwrap();
+    }
+}
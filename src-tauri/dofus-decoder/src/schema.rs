@@ -0,0 +1,594 @@
+use crate::error::DecodeError;
+use log::warn;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+/// Environment variable pointing to the `messages.json` to use
+/// instead of the embedded one.
+pub const SCHEMA_ENV: &str = "DOFUS_MESSAGES_JSON";
+
+/// Environment variable pointing to a directory of `<version>/messages.json`.
+pub const SCHEMA_DIR_ENV: &str = "DOFUS_PROTOCOL_DIR";
+
+/// Protocol version of the embedded definition.
+pub const EMBEDDED_VERSION: &str = "2.64";
+
+const EMBEDDED_MESSAGES: &str = include_str!("../protocol/2.64/messages.json");
+
+/// Wire types the protocol is built upon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrimitiveType {
+    Boolean,
+    Byte,
+    ByteArray,
+    Double,
+    Float,
+    Int,
+    Short,
+    Utf,
+    UnsignedByte,
+    UnsignedInt,
+    UnsignedShort,
+    VarInt,
+    VarLong,
+    VarShort,
+    VarUhInt,
+    VarUhLong,
+    VarUhShort,
+}
+
+impl PrimitiveType {
+    pub fn name(self) -> &'static str {
+        match self {
+            PrimitiveType::Boolean => "Boolean",
+            PrimitiveType::Byte => "Byte",
+            PrimitiveType::ByteArray => "ByteArray",
+            PrimitiveType::Double => "Double",
+            PrimitiveType::Float => "Float",
+            PrimitiveType::Int => "Int",
+            PrimitiveType::Short => "Short",
+            PrimitiveType::Utf => "UTF",
+            PrimitiveType::UnsignedByte => "UnsignedByte",
+            PrimitiveType::UnsignedInt => "UnsignedInt",
+            PrimitiveType::UnsignedShort => "UnsignedShort",
+            PrimitiveType::VarInt => "VarInt",
+            PrimitiveType::VarLong => "VarLong",
+            PrimitiveType::VarShort => "VarShort",
+            PrimitiveType::VarUhInt => "VarUhInt",
+            PrimitiveType::VarUhLong => "VarUhLong",
+            PrimitiveType::VarUhShort => "VarUhShort",
+        }
+    }
+}
+
+impl FromStr for PrimitiveType {
+    type Err = DecodeError;
+
+    fn from_str(name: &str) -> Result<PrimitiveType, DecodeError> {
+        Ok(match name {
+            "Boolean" => PrimitiveType::Boolean,
+            "Byte" => PrimitiveType::Byte,
+            "ByteArray" => PrimitiveType::ByteArray,
+            "Double" => PrimitiveType::Double,
+            "Float" => PrimitiveType::Float,
+            "Int" => PrimitiveType::Int,
+            "Short" => PrimitiveType::Short,
+            "UTF" => PrimitiveType::Utf,
+            "UnsignedByte" => PrimitiveType::UnsignedByte,
+            "UnsignedInt" => PrimitiveType::UnsignedInt,
+            "UnsignedShort" => PrimitiveType::UnsignedShort,
+            "VarInt" => PrimitiveType::VarInt,
+            "VarLong" => PrimitiveType::VarLong,
+            "VarShort" => PrimitiveType::VarShort,
+            "VarUhInt" => PrimitiveType::VarUhInt,
+            "VarUhLong" => PrimitiveType::VarUhLong,
+            "VarUhShort" => PrimitiveType::VarUhShort,
+            _ => return Err(DecodeError::UnknownType(name.to_string())),
+        })
+    }
+}
+
+/// Index of a type in its [`Schema`].
+pub type TypeIndex = usize;
+
+/// What a field holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    Primitive(PrimitiveType),
+    Type(TypeIndex),
+    /// `ID` fields, the concrete type id is written before the value.
+    Polymorphic,
+}
+
+/// How many values a field holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Length {
+    Single,
+    Fixed(u32),
+    /// Array whose size is written first with this type.
+    Prefixed(PrimitiveType),
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldDef {
+    pub name: String,
+    pub kind: FieldKind,
+    pub length: Length,
+    pub optional: bool,
+}
+
+/// A message or type definition.
+#[derive(Debug, Clone)]
+pub struct TypeDef {
+    pub name: String,
+    pub protocol_id: u16,
+    /// Ancestors of the type, the root first.
+    pub parents: Vec<TypeIndex>,
+    /// Flags packed with `BooleanByteWrapper`.
+    pub bool_vars: Vec<String>,
+    pub fields: Vec<FieldDef>,
+    pub hash_function: bool,
+}
+
+/// Protocol definition as exported in `messages.json`, checked when loaded.
+#[derive(Debug, Clone)]
+pub struct Schema {
+    types: Vec<TypeDef>,
+    type_names: HashMap<String, TypeIndex>,
+    messages: HashMap<u16, TypeIndex>,
+    types_by_id: HashMap<u16, TypeIndex>,
+}
+
+impl Schema {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Schema, DecodeError> {
+        let path = path.as_ref();
+
+        fs::read_to_string(path)
+            .map_err(DecodeError::from)
+            .and_then(|data| data.parse())
+            .map_err(|err| DecodeError::SchemaFile(path.to_owned(), Box::new(err)))
+    }
+
+    /// Definition compiled into the binary.
+    pub fn embedded() -> Result<Schema, DecodeError> {
+        EMBEDDED_MESSAGES.parse()
+    }
+
+    pub fn message(&self, id: u16) -> Option<&TypeDef> {
+        self.messages.get(&id).map(|&index| &self.types[index])
+    }
+
+    pub fn type_by_id(&self, id: u16) -> Option<&TypeDef> {
+        self.types_by_id.get(&id).map(|&index| &self.types[index])
+    }
+
+    /// Message named `name`, `None` for other types.
+    pub fn message_by_name(&self, name: &str) -> Option<&TypeDef> {
+        self.type_by_name(name).filter(|type_def| {
+            self.messages.get(&type_def.protocol_id) == self.type_names.get(name)
+        })
+    }
+
+    pub fn type_by_name(&self, name: &str) -> Option<&TypeDef> {
+        self.type_names.get(name).map(|&index| &self.types[index])
+    }
+
+    /// Panics if the index doesn't come from this schema.
+    pub fn type_def(&self, index: TypeIndex) -> &TypeDef {
+        &self.types[index]
+    }
+
+    pub fn types(&self) -> &[TypeDef] {
+        &self.types
+    }
+
+    fn compile(raw: RawSchema) -> Result<Schema, DecodeError> {
+        let mut names: Vec<&String> = raw.types.keys().collect();
+        names.sort();
+        let type_names: HashMap<String, TypeIndex> = names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (name.to_string(), index))
+            .collect();
+
+        let resolve = |name: &str| {
+            type_names
+                .get(name)
+                .copied()
+                .ok_or_else(|| DecodeError::UnknownType(name.to_string()))
+        };
+
+        let mut types = Vec::with_capacity(names.len());
+        for name in names.iter() {
+            let raw_type = &raw.types[*name];
+            let invalid = |reason: String| {
+                DecodeError::InvalidSchema(format!("{}: {}", raw_type.name, reason))
+            };
+
+            if raw_type.name != **name {
+                return Err(invalid(format!("listed as {}", name)));
+            }
+
+            let mut parents = Vec::new();
+            let mut parent = raw_type.parent.as_deref();
+            while let Some(parent_name) = parent {
+                let index = resolve(parent_name).map_err(|err| invalid(err.to_string()))?;
+                if parent_name == raw_type.name || parents.contains(&index) {
+                    return Err(invalid(String::from("circular inheritance")));
+                }
+                parents.push(index);
+                parent = raw.types[parent_name].parent.as_deref();
+            }
+            parents.reverse();
+
+            let fields = raw_type
+                .vars
+                .iter()
+                .map(|var| {
+                    let kind = match var.var_type.as_str() {
+                        "ID" => FieldKind::Polymorphic,
+                        var_type => match var_type.parse() {
+                            Ok(primitive) => FieldKind::Primitive(primitive),
+                            Err(_) => FieldKind::Type(resolve(var_type)?),
+                        },
+                    };
+                    let length = match &var.length {
+                        None => Length::Single,
+                        Some(RawLength::Fixed(size)) => Length::Fixed(*size),
+                        Some(RawLength::Prefixed(length)) => Length::Prefixed(length.parse()?),
+                    };
+
+                    Ok(FieldDef {
+                        name: var.name.clone(),
+                        kind,
+                        length,
+                        optional: var.optional,
+                    })
+                })
+                .collect::<Result<Vec<FieldDef>, DecodeError>>()
+                .map_err(|err| invalid(err.to_string()))?;
+
+            types.push(TypeDef {
+                name: raw_type.name.clone(),
+                protocol_id: raw_type.protocol_id,
+                parents,
+                bool_vars: raw_type
+                    .bool_vars
+                    .iter()
+                    .map(|var| var.name.clone())
+                    .collect(),
+                fields,
+                hash_function: raw_type.hash_function,
+            });
+        }
+
+        let index_ids = |ids: HashMap<String, RawTypeRef>| {
+            ids.into_iter()
+                .map(|(id, type_ref)| {
+                    let id = id.parse().map_err(|_| {
+                        DecodeError::InvalidSchema(format!("{} is not a valid id", id))
+                    })?;
+                    Ok((id, resolve(&type_ref.name)?))
+                })
+                .collect::<Result<HashMap<u16, TypeIndex>, DecodeError>>()
+        };
+
+        Ok(Schema {
+            messages: index_ids(raw.msg_from_id)?,
+            types_by_id: index_ids(raw.types_from_id)?,
+            types,
+            type_names,
+        })
+    }
+}
+
+impl FromStr for Schema {
+    type Err = DecodeError;
+
+    fn from_str(data: &str) -> Result<Schema, DecodeError> {
+        Schema::compile(serde_json::from_str(data)?)
+    }
+}
+
+// `messages.json` as exported, entries of the id tables repeat the type definition
+#[derive(Deserialize)]
+struct RawSchema {
+    types: HashMap<String, RawType>,
+    msg_from_id: HashMap<String, RawTypeRef>,
+    types_from_id: HashMap<String, RawTypeRef>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawType {
+    name: String,
+    parent: Option<String>,
+    protocol_id: u16,
+    vars: Vec<RawVar>,
+    bool_vars: Vec<RawVar>,
+    #[serde(default, rename = "hash_function")]
+    hash_function: bool,
+}
+
+#[derive(Deserialize)]
+struct RawTypeRef {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct RawVar {
+    name: String,
+    length: Option<RawLength>,
+    #[serde(rename = "type")]
+    var_type: String,
+    #[serde(default)]
+    optional: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawLength {
+    Fixed(u32),
+    Prefixed(String),
+}
+
+/// Protocol definitions of several game versions.
+///
+/// Each connection is decoded with the version announced by its
+/// `ProtocolRequired` message, or the default version when it can't be told.
+#[derive(Debug, Clone)]
+pub struct SchemaRegistry {
+    schemas: BTreeMap<String, Arc<Schema>>,
+    default_version: String,
+}
+
+impl SchemaRegistry {
+    pub fn new(version: &str, schema: Schema) -> SchemaRegistry {
+        let mut schemas = BTreeMap::new();
+        schemas.insert(version.to_string(), Arc::new(schema));
+
+        SchemaRegistry {
+            schemas,
+            default_version: version.to_string(),
+        }
+    }
+
+    /// Registry of a single definition, named after its directory.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<SchemaRegistry, DecodeError> {
+        let path = path.as_ref();
+        Ok(SchemaRegistry::new(
+            &version_from_path(path),
+            Schema::from_file(path)?,
+        ))
+    }
+
+    /// The default definition, completed by the versions found in `DOFUS_PROTOCOL_DIR`.
+    pub fn load_default() -> Result<SchemaRegistry, DecodeError> {
+        let mut registry = match env::var_os(SCHEMA_ENV) {
+            Some(path) => SchemaRegistry::from_file(path)?,
+            None => SchemaRegistry::new(EMBEDDED_VERSION, Schema::embedded()?),
+        };
+
+        if let Some(dir) = env::var_os(SCHEMA_DIR_ENV) {
+            registry.load_dir(dir)?;
+        }
+
+        Ok(registry)
+    }
+
+    /// Register every `<version>/messages.json` of a directory.
+    pub fn load_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<(), DecodeError> {
+        let dir = dir.as_ref();
+        let entries = fs::read_dir(dir)
+            .map_err(|err| DecodeError::SchemaFile(dir.to_owned(), Box::new(err.into())))?;
+
+        for entry in entries.flatten() {
+            let path = entry.path().join("messages.json");
+            if path.is_file() {
+                self.insert(&version_from_path(&path), Schema::from_file(path)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn insert(&mut self, version: &str, schema: Schema) {
+        self.schemas.insert(version.to_string(), Arc::new(schema));
+    }
+
+    pub fn get(&self, version: &str) -> Option<Arc<Schema>> {
+        self.schemas.get(version).cloned()
+    }
+
+    pub fn versions(&self) -> Vec<String> {
+        self.schemas.keys().cloned().collect()
+    }
+
+    pub fn default_version(&self) -> &str {
+        &self.default_version
+    }
+
+    /// Version used for connections whose version wasn't detected.
+    pub fn set_default_version(&mut self, version: &str) -> Result<(), DecodeError> {
+        if !self.schemas.contains_key(version) {
+            return Err(DecodeError::UnknownVersion(version.to_string()));
+        }
+        self.default_version = version.to_string();
+        Ok(())
+    }
+
+    pub fn default_schema(&self) -> Arc<Schema> {
+        self.schemas[&self.default_version].clone()
+    }
+
+    /// Find the version of a connection from its first message,
+    /// `None` unless it is a `ProtocolRequired` of a known version.
+    pub fn detect(&self, message: &[u8], has_instance_id: bool) -> Option<String> {
+        let hi_header = u16::from_be_bytes([*message.first()?, *message.get(1)?]);
+        let packet_id = hi_header >> 2;
+        let length_type = (hi_header & 3) as usize;
+        let body = 2 + if has_instance_id { 4 } else { 0 } + length_type;
+
+        // a single UTF field, its length prefix then the version itself
+        let utf_len = u16::from_be_bytes([*message.get(body)?, *message.get(body + 1)?]) as usize;
+        let announced = std::str::from_utf8(message.get(body + 2..body + 2 + utf_len)?).ok()?;
+
+        let candidates: Vec<&String> = self
+            .schemas
+            .iter()
+            .filter(|(_, schema)| {
+                schema
+                    .message(packet_id)
+                    .map(|message| message.name.as_str())
+                    == Some("ProtocolRequired")
+            })
+            .map(|(version, _)| version)
+            .collect();
+
+        // the most precise registered version the announced one belongs to
+        let version = candidates
+            .iter()
+            .filter(|version| version_matches(announced, version))
+            .max_by_key(|version| version.len());
+        if version.is_none() && !candidates.is_empty() {
+            warn!(
+                "Protocol {} is not registered, using {}",
+                announced, self.default_version
+            );
+        }
+        version.map(|version| version.to_string())
+    }
+}
+
+// "2.64" covers "2.64" and "2.64.1", not "2.641"
+fn version_matches(announced: &str, version: &str) -> bool {
+    announced
+        .strip_prefix(version)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+// `.../2.64/messages.json` is version 2.64
+fn version_from_path(path: &Path) -> String {
+    path.parent()
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ProtocolRequired sent by the server, announcing `version`
+    fn protocol_required(version: &str) -> Vec<u8> {
+        let mut bytes = vec![0x8a, 0xe5, version.len() as u8 + 2];
+        bytes.extend_from_slice(&(version.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(version.as_bytes());
+        bytes
+    }
+
+    fn registry(versions: &[&str]) -> SchemaRegistry {
+        let mut registry = SchemaRegistry::new("2.64", Schema::embedded().unwrap());
+        for version in versions {
+            registry.insert(version, Schema::embedded().unwrap());
+        }
+        registry
+    }
+
+    #[test]
+    fn detects_registered_version() {
+        let registry = registry(&["2.6", "2.65"]);
+
+        let detect = |version| registry.detect(&protocol_required(version), false);
+        assert_eq!(detect("2.64").as_deref(), Some("2.64"));
+        assert_eq!(detect("2.64.1.0").as_deref(), Some("2.64"));
+        assert_eq!(detect("2.65.3").as_deref(), Some("2.65"));
+        assert_eq!(detect("2.6.0").as_deref(), Some("2.6"));
+    }
+
+    #[test]
+    fn leaves_unknown_version_to_the_default() {
+        let registry = registry(&["2.6"]);
+
+        assert_eq!(registry.detect(&protocol_required("2.66.0"), false), None);
+        assert_eq!(registry.detect(&protocol_required("2.641"), false), None);
+        // not a ProtocolRequired
+        assert_eq!(registry.detect(&[0x00, 0x05, 0x00], false), None);
+    }
+
+    // `"key": {...}` entry of the types table, `parent` is JSON
+    fn raw_type(key: &str, name: &str, parent: &str, var_type: &str) -> String {
+        format!(
+            r#""{}": {{"name": "{}", "parent": {}, "protocolId": 1, "boolVars": [],
+                "vars": [{{"name": "x", "length": null, "type": "{}"}}]}}"#,
+            key, name, parent, var_type
+        )
+    }
+
+    fn compile(types: &[String], msg_from_id: &str) -> Result<Schema, DecodeError> {
+        format!(
+            r#"{{"types": {{{}}}, "msg_from_id": {{{}}}, "types_from_id": {{}}}}"#,
+            types.join(", "),
+            msg_from_id
+        )
+        .parse()
+    }
+
+    #[test]
+    fn compiles_inline_schema() {
+        let types = [
+            raw_type("Point", "Point", "null", "VarShort"),
+            raw_type("Msg", "Msg", r#""Point""#, "Point"),
+        ];
+        let schema = compile(&types, r#""1": {"name": "Msg"}"#).unwrap();
+
+        let message = schema.message(1).unwrap();
+        assert_eq!(message.name, "Msg");
+        assert_eq!(message.parents, vec![schema.type_names["Point"]]);
+        assert_eq!(
+            message.fields[0].kind,
+            FieldKind::Type(schema.type_names["Point"])
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_schema() {
+        let message = r#""1": {"name": "Msg"}"#;
+        let invalid = |types: &[String], msg_from_id: &str| match compile(types, msg_from_id) {
+            Err(DecodeError::InvalidSchema(reason)) => reason,
+            other => panic!("expected an invalid schema, got {:?}", other.map(|_| ())),
+        };
+
+        let unknown_field = [raw_type("Msg", "Msg", "null", "Nope")];
+        assert_eq!(invalid(&unknown_field, message), "Msg: Unknown type Nope");
+
+        let unknown_parent = [raw_type("Msg", "Msg", r#""Nope""#, "Int")];
+        assert_eq!(invalid(&unknown_parent, message), "Msg: Unknown type Nope");
+
+        let circular = [
+            raw_type("A", "A", r#""B""#, "Int"),
+            raw_type("B", "B", r#""A""#, "Int"),
+        ];
+        assert_eq!(invalid(&circular, ""), "A: circular inheritance");
+
+        let mislisted = [raw_type("Msg", "Other", "null", "Int")];
+        assert_eq!(invalid(&mislisted, ""), "Other: listed as Msg");
+
+        let types = [raw_type("Msg", "Msg", "null", "Int")];
+        assert_eq!(
+            invalid(&types, r#""x1": {"name": "Msg"}"#),
+            "x1 is not a valid id"
+        );
+
+        assert!(matches!(
+            compile(&types, r#""1": {"name": "Nope"}"#),
+            Err(DecodeError::UnknownType(name)) if name == "Nope"
+        ));
+    }
+}
@@ -0,0 +1,454 @@
+use crate::packet_parse::PacketHeader;
+use log::{debug, warn};
+use pktparse::tcp::TcpHeader;
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+// Out-of-order bytes kept per flow while waiting for a missing segment,
+// past that we consider the segment lost and skip the gap.
+const MAX_PENDING_BYTES: usize = 1024 * 1024;
+
+// Same when a segment waited this long, by capture time: a segment dropped by
+// the capture is retransmitted to the host but never seen again.
+const GAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Flows silent for this long are forgotten, their FIN or RST was missed.
+const FLOW_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src_addr: IpAddr,
+    pub src_port: u16,
+    pub dst_addr: IpAddr,
+    pub dst_port: u16,
+}
+
+impl FlowKey {
+    pub fn from_headers(headers: &[PacketHeader]) -> Option<(FlowKey, &TcpHeader)> {
+        let mut tcp = None;
+        let mut addrs = None;
+
+        headers.iter().for_each(|header| match header {
+            PacketHeader::Tcp(header) => tcp = Some(header),
+            PacketHeader::Ipv4(header) => {
+                addrs = Some((IpAddr::V4(header.source_addr), IpAddr::V4(header.dest_addr)))
+            }
+            PacketHeader::Ipv6(header) => {
+                addrs = Some((IpAddr::V6(header.source_addr), IpAddr::V6(header.dest_addr)))
+            }
+            _ => {}
+        });
+
+        let tcp = tcp?;
+        let (src_addr, dst_addr) = addrs?;
+
+        Some((
+            FlowKey {
+                src_addr,
+                src_port: tcp.source_port,
+                dst_addr,
+                dst_port: tcp.dest_port,
+            },
+            tcp,
+        ))
+    }
+
+    /// Key of the other direction of the connection.
+    pub fn reverse(&self) -> FlowKey {
+        FlowKey {
+            src_addr: self.dst_addr,
+            src_port: self.dst_port,
+            dst_addr: self.src_addr,
+            dst_port: self.src_port,
+        }
+    }
+
+    /// Same key for both directions of a connection.
+    pub fn connection(&self) -> FlowKey {
+        if (self.src_addr, self.src_port) <= (self.dst_addr, self.dst_port) {
+            *self
+        } else {
+            self.reverse()
+        }
+    }
+}
+
+/// Contiguous bytes of a flow, ready for the decoder.
+/// `gap` is set when data had to be skipped before `payload`,
+/// anything the decoder buffered for this flow is then stale.
+#[derive(Debug)]
+pub struct StreamData {
+    pub flow: FlowKey,
+    pub gap: bool,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct TcpFlow {
+    next_seq: Option<u32>,
+    // sequence number, bytes and capture time of the segments past a gap
+    pending: Vec<(u32, Vec<u8>, Duration)>,
+    pending_bytes: usize,
+    last_seen: Duration,
+}
+
+// signed distance between two sequence numbers, handles wrap around
+fn seq_diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
+}
+
+impl TcpFlow {
+    fn push(&mut self, seq: u32, payload: &[u8], time: Duration, out: &mut Vec<u8>) -> bool {
+        let next_seq = *self.next_seq.get_or_insert(seq);
+        let offset = seq_diff(seq, next_seq);
+
+        if offset > 0 {
+            if !self
+                .pending
+                .iter()
+                .any(|(s, p, _)| *s == seq && p.len() >= payload.len())
+            {
+                self.pending.push((seq, payload.to_owned(), time));
+                self.pending_bytes += payload.len();
+            }
+        } else {
+            self.append(seq, payload, out);
+        }
+
+        let mut gap = false;
+        loop {
+            self.drain_pending(out);
+
+            let stalled = self
+                .pending
+                .iter()
+                .any(|(_, _, since)| time.saturating_sub(*since) > GAP_TIMEOUT);
+            if self.pending_bytes <= MAX_PENDING_BYTES && !stalled {
+                break;
+            }
+
+            // the missing segment never came, resume at the first one we hold
+            let next_seq = self.next_seq.unwrap_or_default();
+            if let Some(&(first, _, _)) = self
+                .pending
+                .iter()
+                .min_by_key(|(s, _, _)| seq_diff(*s, next_seq))
+            {
+                warn!(
+                    "TCP gap of {} bytes, skipping lost segment",
+                    seq_diff(first, next_seq)
+                );
+                self.next_seq = Some(first);
+                gap = true;
+            }
+        }
+        gap
+    }
+
+    fn append(&mut self, seq: u32, payload: &[u8], out: &mut Vec<u8>) {
+        let next_seq = self.next_seq.unwrap_or(seq);
+        let already_seen = seq_diff(next_seq, seq).max(0) as usize;
+
+        if already_seen >= payload.len() {
+            debug!("Duplicate TCP segment seq {}", seq);
+            return;
+        }
+
+        let fresh = &payload[already_seen..];
+        out.extend_from_slice(fresh);
+        self.next_seq = Some(next_seq.wrapping_add(fresh.len() as u32));
+    }
+
+    fn drain_pending(&mut self, out: &mut Vec<u8>) {
+        while let Some(next_seq) = self.next_seq {
+            let ready = self
+                .pending
+                .iter()
+                .position(|(s, _, _)| seq_diff(*s, next_seq) <= 0);
+
+            match ready {
+                Some(index) => {
+                    let (seq, payload, _) = self.pending.swap_remove(index);
+                    self.pending_bytes -= payload.len();
+                    self.append(seq, &payload, out);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TcpReassembler {
+    flows: HashMap<FlowKey, TcpFlow>,
+    // flows forgotten since the last `closed_flows`
+    closed: Vec<FlowKey>,
+}
+
+impl TcpReassembler {
+    pub fn new() -> TcpReassembler {
+        TcpReassembler {
+            flows: HashMap::new(),
+            closed: vec![],
+        }
+    }
+
+    /// Flows that ended or went idle since the last call, nothing more will come from them.
+    pub fn closed_flows(&mut self) -> Vec<FlowKey> {
+        std::mem::take(&mut self.closed)
+    }
+
+    /// Whether segments of `flow` are still expected.
+    pub fn is_open(&self, flow: &FlowKey) -> bool {
+        self.flows.contains_key(flow)
+    }
+
+    /// Feed a segment captured at `time`, returns the bytes it made contiguous.
+    pub fn push(
+        &mut self,
+        flow: FlowKey,
+        header: &TcpHeader,
+        payload: &[u8],
+        time: Duration,
+    ) -> Option<StreamData> {
+        self.expire(time);
+
+        // a reset ends both directions at once
+        if header.flag_rst {
+            self.close(flow);
+            self.close(flow.reverse());
+            return None;
+        }
+
+        let tcp_flow = self.flows.entry(flow).or_default();
+        tcp_flow.last_seen = time;
+
+        if header.flag_syn {
+            // a new connection reuses the 4-tuple, forget the previous one
+            *tcp_flow = TcpFlow::default();
+            tcp_flow.next_seq = Some(header.sequence_no.wrapping_add(1));
+        }
+
+        let mut stream = StreamData {
+            flow,
+            gap: false,
+            payload: vec![],
+        };
+
+        if !payload.is_empty() {
+            let seq = if header.flag_syn {
+                header.sequence_no.wrapping_add(1)
+            } else {
+                header.sequence_no
+            };
+            stream.gap = tcp_flow.push(seq, payload, time, &mut stream.payload);
+        }
+
+        if header.flag_fin && tcp_flow.pending.is_empty() {
+            self.close(flow);
+        }
+
+        if stream.payload.is_empty() {
+            None
+        } else {
+            Some(stream)
+        }
+    }
+
+    fn close(&mut self, flow: FlowKey) {
+        if self.flows.remove(&flow).is_some() {
+            self.closed.push(flow);
+        }
+    }
+
+    fn expire(&mut self, time: Duration) {
+        let closed = &mut self.closed;
+        self.flows.retain(|flow, tcp_flow| {
+            let idle = time.saturating_sub(tcp_flow.last_seen) > FLOW_TIMEOUT;
+            if idle {
+                debug!(
+                    "TCP flow {}:{} -> {}:{} idle, forgetting it",
+                    flow.src_addr, flow.src_port, flow.dst_addr, flow.dst_port
+                );
+                closed.push(*flow);
+            }
+            !idle
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn flow() -> FlowKey {
+        FlowKey {
+            src_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            src_port: 5555,
+            dst_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            dst_port: 40000,
+        }
+    }
+
+    fn header(sequence_no: u32) -> TcpHeader {
+        TcpHeader {
+            source_port: 5555,
+            dest_port: 40000,
+            sequence_no,
+            ack_no: 0,
+            data_offset: 5,
+            reserved: 0,
+            flag_urg: false,
+            flag_ack: true,
+            flag_psh: false,
+            flag_rst: false,
+            flag_syn: false,
+            flag_fin: false,
+            window: 0,
+            checksum: 0,
+            urgent_pointer: 0,
+            options: None,
+        }
+    }
+
+    fn push(reassembler: &mut TcpReassembler, seq: u32, payload: &[u8]) -> Option<StreamData> {
+        reassembler.push(flow(), &header(seq), payload, Duration::ZERO)
+    }
+
+    fn bytes(stream: Option<StreamData>) -> Vec<u8> {
+        stream.map(|stream| stream.payload).unwrap_or_default()
+    }
+
+    #[test]
+    fn orders_segments() {
+        let mut reassembler = TcpReassembler::new();
+
+        assert_eq!(bytes(push(&mut reassembler, 100, b"ab")), b"ab");
+        assert!(push(&mut reassembler, 104, b"ef").is_none());
+        assert_eq!(bytes(push(&mut reassembler, 102, b"cd")), b"cdef");
+        assert_eq!(bytes(push(&mut reassembler, 106, b"g")), b"g");
+    }
+
+    #[test]
+    fn starts_after_syn() {
+        let mut reassembler = TcpReassembler::new();
+        let mut syn = header(99);
+        syn.flag_syn = true;
+
+        assert!(reassembler
+            .push(flow(), &syn, &[], Duration::ZERO)
+            .is_none());
+        assert!(push(&mut reassembler, 102, b"cd").is_none());
+        assert_eq!(bytes(push(&mut reassembler, 100, b"ab")), b"abcd");
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let mut reassembler = TcpReassembler::new();
+
+        assert_eq!(bytes(push(&mut reassembler, 100, b"abcd")), b"abcd");
+        assert!(push(&mut reassembler, 100, b"abcd").is_none());
+        assert!(push(&mut reassembler, 102, b"cd").is_none());
+        // retransmission overlapping new bytes only gives the new ones
+        assert_eq!(bytes(push(&mut reassembler, 102, b"cdef")), b"ef");
+
+        // the same out-of-order segment twice is only delivered once
+        assert!(push(&mut reassembler, 108, b"ij").is_none());
+        assert!(push(&mut reassembler, 108, b"ij").is_none());
+        assert_eq!(bytes(push(&mut reassembler, 106, b"gh")), b"ghij");
+    }
+
+    #[test]
+    fn skips_lost_segment() {
+        let mut reassembler = TcpReassembler::new();
+        assert_eq!(bytes(push(&mut reassembler, 0, b"a")), b"a");
+
+        // the segment at 1 never comes, the next ones pile up
+        let chunk = vec![7; 64 * 1024];
+        let mut seq = 1 + 1000;
+        for _ in 0..MAX_PENDING_BYTES / chunk.len() {
+            assert!(push(&mut reassembler, seq, &chunk).is_none());
+            seq += chunk.len() as u32;
+        }
+
+        let stream = push(&mut reassembler, seq, &chunk).unwrap();
+        assert!(stream.gap);
+        assert_eq!(stream.payload.len(), MAX_PENDING_BYTES + chunk.len());
+    }
+
+    #[test]
+    fn skips_segment_lost_for_long() {
+        let mut reassembler = TcpReassembler::new();
+        assert_eq!(bytes(push(&mut reassembler, 0, b"a")), b"a");
+
+        // the segment at 1 is retransmitted in time, then the one at 3 is not
+        let retransmitted = GAP_TIMEOUT - Duration::from_millis(1);
+        assert!(push(&mut reassembler, 2, b"c").is_none());
+        let stream = reassembler.push(flow(), &header(1), b"b", retransmitted);
+        assert_eq!(bytes(stream), b"bc");
+
+        assert!(push(&mut reassembler, 4, b"e").is_none());
+        let later = GAP_TIMEOUT + Duration::from_secs(1);
+        let stream = reassembler.push(flow(), &header(6), b"g", later).unwrap();
+        assert!(stream.gap);
+        // the newest segment is behind another gap, still in time
+        assert_eq!(stream.payload, b"e");
+
+        let stream = reassembler.push(flow(), &header(5), b"f", later).unwrap();
+        assert!(!stream.gap);
+        assert_eq!(stream.payload, b"fg");
+    }
+
+    #[test]
+    fn wraps_sequence_numbers() {
+        let mut reassembler = TcpReassembler::new();
+
+        assert_eq!(bytes(push(&mut reassembler, u32::MAX - 1, b"ab")), b"ab");
+        assert!(push(&mut reassembler, 2, b"ef").is_none());
+        assert_eq!(bytes(push(&mut reassembler, 0, b"cd")), b"cdef");
+        // before the wrap is still in the past
+        assert!(push(&mut reassembler, u32::MAX, b"bcd").is_none());
+    }
+
+    #[test]
+    fn forgets_idle_flows() {
+        let mut reassembler = TcpReassembler::new();
+
+        assert_eq!(bytes(push(&mut reassembler, 100, b"ab")), b"ab");
+        assert!(push(&mut reassembler, 104, b"ef").is_none());
+
+        // the pending segment is gone with the flow, which restarts where it is picked up
+        let later = FLOW_TIMEOUT + Duration::from_secs(1);
+        let stream = reassembler.push(flow(), &header(500), b"xy", later);
+        assert_eq!(bytes(stream), b"xy");
+        assert_eq!(reassembler.closed_flows(), vec![flow()]);
+        assert!(reassembler.is_open(&flow()));
+    }
+
+    #[test]
+    fn forgets_closed_flows() {
+        let mut reassembler = TcpReassembler::new();
+        assert_eq!(bytes(push(&mut reassembler, 100, b"ab")), b"ab");
+
+        let mut fin = header(102);
+        fin.flag_fin = true;
+        assert!(reassembler
+            .push(flow(), &fin, &[], Duration::ZERO)
+            .is_none());
+        assert!(!reassembler.is_open(&flow()));
+        assert_eq!(reassembler.closed_flows(), vec![flow()]);
+        assert!(reassembler.closed_flows().is_empty());
+
+        // a reset from one side ends both directions
+        assert_eq!(bytes(push(&mut reassembler, 100, b"ab")), b"ab");
+        let reply = reassembler.push(flow().reverse(), &header(900), b"cd", Duration::ZERO);
+        assert_eq!(bytes(reply), b"cd");
+        let mut rst = header(102);
+        rst.flag_rst = true;
+        assert!(reassembler
+            .push(flow(), &rst, &[], Duration::ZERO)
+            .is_none());
+        assert!(reassembler.flows.is_empty());
+        assert_eq!(reassembler.closed_flows(), vec![flow(), flow().reverse()]);
+    }
+}
@@ -0,0 +1,263 @@
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::ops::Range;
+
+/// Key holding the concrete type of a polymorphic value.
+pub const TYPE_KEY: &str = "__type__";
+
+// largest integer a javascript number holds exactly
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// Decoded value, keeping the wire type it was read as.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodedValue {
+    /// Optional field that wasn't sent.
+    Null,
+    Boolean(bool),
+    Byte(i8),
+    UnsignedByte(u8),
+    Short(i16),
+    UnsignedShort(u16),
+    Int(i32),
+    UnsignedInt(u32),
+    Float(f32),
+    Double(f64),
+    Utf(String),
+    ByteArray(Vec<u8>),
+    VarShort(i16),
+    VarUhShort(u16),
+    VarInt(i32),
+    VarUhInt(u32),
+    VarLong(i64),
+    VarUhLong(u64),
+    Array(Vec<DecodedValue>),
+    Struct(Struct),
+    /// Value of an `ID` field, preceded on the wire by the id of its type.
+    Polymorphic {
+        type_id: u16,
+        value: Struct,
+    },
+}
+
+impl DecodedValue {
+    /// Integer value, if the wire type is an integer one.
+    pub fn as_i64(&self) -> Option<i64> {
+        Some(match *self {
+            DecodedValue::Byte(v) => v.into(),
+            DecodedValue::UnsignedByte(v) => v.into(),
+            DecodedValue::Short(v) | DecodedValue::VarShort(v) => v.into(),
+            DecodedValue::UnsignedShort(v) | DecodedValue::VarUhShort(v) => v.into(),
+            DecodedValue::Int(v) | DecodedValue::VarInt(v) => v.into(),
+            DecodedValue::UnsignedInt(v) | DecodedValue::VarUhInt(v) => v.into(),
+            DecodedValue::VarLong(v) => v,
+            DecodedValue::VarUhLong(v) => return v.try_into().ok(),
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            DecodedValue::Utf(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[DecodedValue]> {
+        match self {
+            DecodedValue::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Fields of a struct, whether polymorphic or not.
+    pub fn as_struct(&self) -> Option<&Struct> {
+        match self {
+            DecodedValue::Struct(v) | DecodedValue::Polymorphic { value: v, .. } => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Fields of a message or type, inherited ones first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Struct {
+    pub type_name: String,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    /// Position of the field bytes in the message.
+    pub offset: usize,
+    pub length: usize,
+    pub value: DecodedValue,
+}
+
+/// Bytes a field was read from, `path` being like `fighters[2].stats.lifePoints`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldSpan {
+    pub path: String,
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl Struct {
+    pub fn new(type_name: &str) -> Struct {
+        Struct {
+            type_name: type_name.to_string(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, name: &str, bytes: Range<usize>, value: DecodedValue) {
+        self.fields.push(Field {
+            name: name.to_string(),
+            offset: bytes.start,
+            length: bytes.len(),
+            value,
+        });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&DecodedValue> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)
+            .map(|field| &field.value)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut DecodedValue> {
+        self.fields
+            .iter_mut()
+            .find(|field| field.name == name)
+            .map(|field| &mut field.value)
+    }
+
+    /// Spans of every field, nested ones included, in wire order.
+    pub fn layout(&self) -> Vec<FieldSpan> {
+        let mut spans = Vec::new();
+        self.collect_spans("", &mut spans);
+        spans
+    }
+
+    fn collect_spans(&self, prefix: &str, spans: &mut Vec<FieldSpan>) {
+        for field in self.fields.iter() {
+            let path = format!("{}{}", prefix, field.name);
+            spans.push(FieldSpan {
+                path: path.clone(),
+                offset: field.offset,
+                length: field.length,
+            });
+
+            match &field.value {
+                DecodedValue::Array(items) => {
+                    for (i, item) in items.iter().enumerate() {
+                        if let Some(value) = item.as_struct() {
+                            value.collect_spans(&format!("{}[{}].", path, i), spans);
+                        }
+                    }
+                }
+                value => {
+                    if let Some(value) = value.as_struct() {
+                        value.collect_spans(&format!("{}.", path), spans);
+                    }
+                }
+            }
+        }
+    }
+
+    fn serialize_fields<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
+        for field in self.fields.iter() {
+            map.serialize_entry(&field.name, &field.value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Struct {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+        self.serialize_fields(&mut map)?;
+        map.end()
+    }
+}
+
+impl Serialize for DecodedValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            DecodedValue::Null => serializer.serialize_none(),
+            DecodedValue::Boolean(v) => serializer.serialize_bool(*v),
+            DecodedValue::Byte(v) => serializer.serialize_i8(*v),
+            DecodedValue::UnsignedByte(v) => serializer.serialize_u8(*v),
+            DecodedValue::Short(v) | DecodedValue::VarShort(v) => serializer.serialize_i16(*v),
+            DecodedValue::UnsignedShort(v) | DecodedValue::VarUhShort(v) => {
+                serializer.serialize_u16(*v)
+            }
+            DecodedValue::Int(v) | DecodedValue::VarInt(v) => serializer.serialize_i32(*v),
+            DecodedValue::UnsignedInt(v) | DecodedValue::VarUhInt(v) => {
+                serializer.serialize_u32(*v)
+            }
+            DecodedValue::Float(v) => serializer.serialize_f32(*v),
+            DecodedValue::Double(v) => serializer.serialize_f64(*v),
+            DecodedValue::Utf(v) => serializer.serialize_str(v),
+            DecodedValue::ByteArray(v) => v.serialize(serializer),
+            // beyond 2^53 a javascript number would round the value
+            DecodedValue::VarLong(v) => {
+                if v.unsigned_abs() <= MAX_SAFE_INTEGER {
+                    serializer.serialize_i64(*v)
+                } else {
+                    serializer.collect_str(v)
+                }
+            }
+            DecodedValue::VarUhLong(v) => {
+                if *v <= MAX_SAFE_INTEGER {
+                    serializer.serialize_u64(*v)
+                } else {
+                    serializer.collect_str(v)
+                }
+            }
+            DecodedValue::Array(v) => v.serialize(serializer),
+            DecodedValue::Struct(v) => v.serialize(serializer),
+            DecodedValue::Polymorphic { value, .. } => {
+                let mut map = serializer.serialize_map(Some(value.fields.len() + 1))?;
+                map.serialize_entry(TYPE_KEY, &value.type_name)?;
+                value.serialize_fields(&mut map)?;
+                map.end()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, to_value};
+
+    #[test]
+    fn keeps_safe_integers_as_numbers() {
+        let safe = MAX_SAFE_INTEGER as i64;
+        for &value in &[0, safe, -safe] {
+            let json = to_value(DecodedValue::VarLong(value)).unwrap();
+            assert_eq!(json, json!(value));
+        }
+        let json = to_value(DecodedValue::VarUhLong(MAX_SAFE_INTEGER)).unwrap();
+        assert_eq!(json, json!(MAX_SAFE_INTEGER));
+    }
+
+    #[test]
+    fn writes_unsafe_integers_as_strings() {
+        let past = MAX_SAFE_INTEGER as i64 + 1;
+        for &value in &[past, -past, i64::MIN, i64::MAX] {
+            let json = to_value(DecodedValue::VarLong(value)).unwrap();
+            assert_eq!(json, json!(value.to_string()));
+        }
+        for &value in &[MAX_SAFE_INTEGER + 1, u64::MAX] {
+            let json = to_value(DecodedValue::VarUhLong(value)).unwrap();
+            assert_eq!(json, json!(value.to_string()));
+        }
+    }
+
+    #[test]
+    fn writes_absent_value_as_null() {
+        assert_eq!(to_value(DecodedValue::Null).unwrap(), json!(null));
+    }
+}
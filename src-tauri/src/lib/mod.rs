@@ -1,3 +0,0 @@
-pub mod packet_capture;
-pub mod packet_decoder;
-pub mod packet_parse;
@@ -3,14 +3,26 @@
     windows_subsystem = "windows"
 )]
 
-mod lib;
-use lib::{
-    packet_capture::PacketCapture,
-    packet_decoder::{DofusPacket, PacketDecoder},
+use dofus_decoder::{
+    game_servers::{capture_filter, DEFAULT_FILTER},
+    packet_capture::{PacketCapture, ReplayClock},
+    DofusPacket, PacketDecoder, Proxy, Schema,
 };
-use pcap::{Capture, Device};
-use serde::Serialize;
-use tauri::Manager;
+use log::{error, info, warn};
+use pcap::{Activated, Active, Capture, Device};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::ToSocketAddrs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+};
+use tauri::{AppHandle, Manager};
+
+// How long a live capture waits for packets before checking if it was stopped
+const CAPTURE_TIMEOUT_MS: i32 = 500;
 
 #[derive(Clone, serde::Serialize)]
 struct Payload {
@@ -27,10 +39,255 @@ impl ServerMessage {
     }
 }
 
+// Decoder shared by the capture task and the commands
+struct DecoderState(Arc<Mutex<PacketDecoder>>);
+
+/// Options of the live capture, applied when it is (re)started.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CaptureSettings {
+    /// Interface to capture on, the default one of the system when `None`.
+    device: Option<String>,
+    filter: String,
+    snaplen: i32,
+    promisc: bool,
+    /// Kernel buffer size in bytes, the libpcap default when `None`.
+    buffer_size: Option<i32>,
+}
+
+impl Default for CaptureSettings {
+    fn default() -> CaptureSettings {
+        CaptureSettings {
+            device: None,
+            filter: DEFAULT_FILTER.to_string(),
+            snaplen: 65535,
+            promisc: false,
+            buffer_size: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DeviceInfo {
+    name: String,
+    description: Option<String>,
+    addresses: Vec<String>,
+    loopback: bool,
+}
+
+impl From<Device> for DeviceInfo {
+    fn from(device: Device) -> DeviceInfo {
+        DeviceInfo {
+            addresses: device
+                .addresses
+                .iter()
+                .map(|address| address.addr.to_string())
+                .collect(),
+            loopback: device.flags.is_loopback(),
+            name: device.name,
+            description: device.desc,
+        }
+    }
+}
+
+struct RunningCapture {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+// Live capture settings, and the thread capturing with them once started
+#[derive(Default)]
+struct LiveCapture {
+    settings: CaptureSettings,
+    running: Option<RunningCapture>,
+}
+
+struct CaptureState(Mutex<LiveCapture>);
+
+impl LiveCapture {
+    fn start<R: tauri::Runtime>(
+        &mut self,
+        decoder: Arc<Mutex<PacketDecoder>>,
+        app_handle: AppHandle<R>,
+    ) -> Result<(), String> {
+        // the flag is also set by a capture that ended on its own
+        if let Some(running) = &self.running {
+            if !running.stop.load(Ordering::Relaxed) {
+                return Err(String::from("The capture is already running"));
+            }
+        }
+        self.stop();
+
+        let mut cap = open_capture(&self.settings)?;
+        let filter = self.settings.filter.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                if let Err(err) =
+                    decode_capture(&mut cap, &filter, &decoder, None, &stop, &app_handle)
+                {
+                    error!("Capture interrupted: {}", err);
+                    let _ = app_handle.emit_all("capture-error", err.to_string());
+                }
+                stop.store(true, Ordering::Relaxed);
+            })
+        };
+
+        self.running = Some(RunningCapture { stop, thread });
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(running) = self.running.take() {
+            running.stop.store(true, Ordering::Relaxed);
+            if running.thread.join().is_err() {
+                warn!("The capture thread panicked");
+            }
+        }
+    }
+}
+
+fn open_capture(settings: &CaptureSettings) -> Result<Capture<Active>, String> {
+    let device = match &settings.device {
+        Some(name) => Device::from(name.as_str()),
+        None => Device::lookup()
+            .map_err(|err| format!("Device lookup failed: {}", err))?
+            .ok_or("No device available")?,
+    };
+    let name = device.name.clone();
+    let open_error = |err: pcap::Error| format!("Unable to capture on {}: {}", name, err);
+
+    let mut cap = Capture::from_device(device)
+        .map_err(open_error)?
+        .immediate_mode(true)
+        .timeout(CAPTURE_TIMEOUT_MS)
+        .snaplen(settings.snaplen)
+        .promisc(settings.promisc);
+    if let Some(buffer_size) = settings.buffer_size {
+        cap = cap.buffer_size(buffer_size);
+    }
+
+    let mut cap = cap.open().map_err(open_error)?;
+    cap.filter(&capture_filter(&settings.filter), true)
+        .map_err(|err| format!("Invalid filter \"{}\": {}", settings.filter, err))?;
+    Ok(cap)
+}
+
+/// Register the `messages.json` at `path` as protocol `version`, it becomes
+/// the default for connections whose version can't be detected.
+#[tauri::command]
+fn load_protocol(
+    version: String,
+    path: String,
+    state: tauri::State<DecoderState>,
+) -> Result<(), String> {
+    let schema = Schema::from_file(path).map_err(|err| err.to_string())?;
+
+    let mut decoder = state.0.lock().unwrap_or_else(PoisonError::into_inner);
+    decoder.schemas_mut().insert(&version, schema);
+    decoder
+        .schemas_mut()
+        .set_default_version(&version)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn protocol_versions(state: tauri::State<DecoderState>) -> Vec<String> {
+    let decoder = state.0.lock().unwrap_or_else(PoisonError::into_inner);
+    decoder.schemas().versions()
+}
+
+/// Protocol version used when it can't be detected from the handshake.
+#[tauri::command]
+fn set_protocol_version(version: String, state: tauri::State<DecoderState>) -> Result<(), String> {
+    let mut decoder = state.0.lock().unwrap_or_else(PoisonError::into_inner);
+    decoder
+        .schemas_mut()
+        .set_default_version(&version)
+        .map_err(|err| err.to_string())
+}
+
+/// Interfaces available for a live capture.
+#[tauri::command(async)]
+fn list_devices() -> Result<Vec<DeviceInfo>, String> {
+    let devices = Device::list().map_err(|err| err.to_string())?;
+    Ok(devices.into_iter().map(DeviceInfo::from).collect())
+}
+
+#[tauri::command]
+fn capture_settings(state: tauri::State<CaptureState>) -> CaptureSettings {
+    let capture = state.0.lock().unwrap_or_else(PoisonError::into_inner);
+    capture.settings.clone()
+}
+
+/// Change the live capture options, they apply from its next start.
+#[tauri::command(async)]
+fn set_capture_settings(
+    settings: CaptureSettings,
+    state: tauri::State<CaptureState>,
+) -> Result<(), String> {
+    if let Some(name) = &settings.device {
+        let devices = Device::list().map_err(|err| err.to_string())?;
+        if !devices.iter().any(|device| &device.name == name) {
+            return Err(format!("Unknown device {}", name));
+        }
+    }
+    if settings.snaplen <= 0 {
+        return Err(format!("Invalid snaplen {}", settings.snaplen));
+    }
+    if let Some(buffer_size) = settings.buffer_size.filter(|size| *size <= 0) {
+        return Err(format!("Invalid buffer size {}", buffer_size));
+    }
+
+    let mut capture = state.0.lock().unwrap_or_else(PoisonError::into_inner);
+    capture.settings = settings;
+    Ok(())
+}
+
+#[tauri::command(async)]
+fn start_capture(
+    app_handle: AppHandle,
+    decoder: tauri::State<DecoderState>,
+    state: tauri::State<CaptureState>,
+) -> Result<(), String> {
+    let mut capture = state.0.lock().unwrap_or_else(PoisonError::into_inner);
+    capture.start(decoder.0.clone(), app_handle)
+}
+
+#[tauri::command(async)]
+fn stop_capture(state: tauri::State<CaptureState>) {
+    let mut capture = state.0.lock().unwrap_or_else(PoisonError::into_inner);
+    capture.stop();
+}
+
+/// Stop the capture if it runs and start it again, with the current settings.
+#[tauri::command(async)]
+fn restart_capture(
+    app_handle: AppHandle,
+    decoder: tauri::State<DecoderState>,
+    state: tauri::State<CaptureState>,
+) -> Result<(), String> {
+    let mut capture = state.0.lock().unwrap_or_else(PoisonError::into_inner);
+    capture.stop();
+    capture.start(decoder.0.clone(), app_handle)
+}
+
 fn main() {
     env_logger::init();
 
     tauri::Builder::default()
+        .invoke_handler(tauri::generate_handler![
+            load_protocol,
+            protocol_versions,
+            set_protocol_version,
+            list_devices,
+            capture_settings,
+            set_capture_settings,
+            start_capture,
+            stop_capture,
+            restart_capture
+        ])
         .setup(|app| {
             let id = app.listen_global("event-name", |event| {
                 println!("got event-name with payload {:?}", event.payload());
@@ -38,40 +295,26 @@ fn main() {
 
             app.unlisten(id);
 
-            let device = Device::lookup()
-                .expect("device lookup failed")
-                .expect("no device available");
-
-            let mut cap = Capture::from_device(device)
-                .unwrap()
-                .immediate_mode(true)
-                .open()
-                .unwrap();
-            cap.filter("tcp port 5555", true).unwrap();
             let app_handle = app.handle();
+            let decoder = Arc::new(Mutex::new(PacketDecoder::new()?));
+            app.manage(DecoderState(decoder.clone()));
+            app.manage(CaptureState(Mutex::new(LiveCapture::default())));
 
-            tauri::async_runtime::spawn(async move {
-                loop {
-                    let mut decoder = PacketDecoder::new();
-                    let mut packet_capture = PacketCapture::new();
-                    while let Ok(packet) = cap.next_packet() {
-                        // parsed.remaining flush data so we lose the len value for the dofus decoder.
-                        // still needed for know if this is client or server
-                        let parsed = packet_capture.get_packet(&packet);
-                        let (_src_addr, src_port, _dst_addr, _dst_port) =
-                            packet_capture.get_packet_meta(&parsed);
-
-                        // we remove the header from the data, slice at 54
-                        let tcp_content = &packet.data[54..];
-
-                        decoder.decode_packet(tcp_content, src_port.parse().unwrap_or_default());
-                        let messages = decoder.get_messages();
-                        let server_message = ServerMessage::new(messages);
-
-                        rs2js(serde_json::to_string(&server_message).unwrap(), &app_handle);
-                    }
+            let args: Vec<String> = std::env::args().skip(1).collect();
+            let started = match parse_args(&args) {
+                Ok(StartMode::Replay(path)) => start_replay(path, decoder, app_handle),
+                Ok(StartMode::Proxy { listen, upstream }) => {
+                    start_proxy(&listen, &upstream, decoder, app_handle)
                 }
-            });
+                Ok(StartMode::Live) => start_live(app, decoder, app_handle),
+                Err(err) => {
+                    error!("{}", err);
+                    start_live(app, decoder, app_handle)
+                }
+            };
+            if let Err(err) = started {
+                error!("{}", err);
+            }
 
             Ok(())
         })
@@ -79,6 +322,146 @@ fn main() {
         .expect("error while running tauri application");
 }
 
+const USAGE: &str = "usage: tauri-app [--replay <FILE> | --proxy <LISTEN> <UPSTREAM>]";
+
+// What the app decodes, picked from the command line
+enum StartMode {
+    Live,
+    Replay(String),
+    Proxy { listen: String, upstream: String },
+}
+
+fn parse_args(args: &[String]) -> Result<StartMode, String> {
+    match args {
+        [] => Ok(StartMode::Live),
+        [flag, path] if flag == "--replay" => Ok(StartMode::Replay(path.clone())),
+        [flag, listen, upstream] if flag == "--proxy" => Ok(StartMode::Proxy {
+            listen: listen.clone(),
+            upstream: upstream.clone(),
+        }),
+        _ => Err(format!(
+            "Unexpected arguments {}, sniffing instead\n{}",
+            args.join(" "),
+            USAGE
+        )),
+    }
+}
+
+// sniff the default device, the UI can pick another one or retry
+fn start_live(
+    app: &tauri::App,
+    decoder: Arc<Mutex<PacketDecoder>>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    let state = app.state::<CaptureState>();
+    let mut capture = state.0.lock().unwrap_or_else(PoisonError::into_inner);
+    capture.start(decoder, app_handle)
+}
+
+// decode a capture file instead of sniffing, with its recorded timing
+fn start_replay(
+    path: String,
+    decoder: Arc<Mutex<PacketDecoder>>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    let open_error = |err: pcap::Error| format!("Unable to open {}: {}", path, err);
+    let mut cap = Capture::from_file(&path).map_err(open_error)?;
+    cap.filter(&capture_filter(DEFAULT_FILTER), true)
+        .map_err(open_error)?;
+
+    thread::spawn(move || {
+        let clock = Some(ReplayClock::new());
+        let stop = AtomicBool::new(false);
+        match decode_capture(
+            &mut cap,
+            DEFAULT_FILTER,
+            &decoder,
+            clock,
+            &stop,
+            &app_handle,
+        ) {
+            Err(pcap::Error::NoMorePackets) => info!("End of capture file {}", path),
+            Err(err) => error!("Unable to read {}: {}", path, err),
+            Ok(()) => {}
+        }
+    });
+
+    Ok(())
+}
+
+// relay the game connections instead of sniffing
+fn start_proxy(
+    listen: &str,
+    upstream: &str,
+    decoder: Arc<Mutex<PacketDecoder>>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    let upstream = upstream
+        .to_socket_addrs()
+        .map_err(|err| format!("Invalid upstream {}: {}", upstream, err))?
+        .next()
+        .ok_or_else(|| format!("Upstream {} not found", upstream))?;
+    let proxy = Proxy::bind(listen, upstream)
+        .map_err(|err| format!("Unable to listen on {}: {}", listen, err))?;
+    info!("Listening on {}, relaying to {}", listen, upstream);
+
+    thread::spawn(move || {
+        let on_messages = move |messages| {
+            let server_message = ServerMessage::new(messages);
+            rs2js(serde_json::to_string(&server_message).unwrap(), &app_handle);
+        };
+        let stop = AtomicBool::new(false);
+        if let Err(err) = proxy.run(decoder, Arc::new(on_messages), &stop) {
+            error!("Proxy stopped: {}", err);
+        }
+    });
+
+    Ok(())
+}
+
+// decode until the capture ends, fails or `stop` is set
+fn decode_capture<T: Activated + ?Sized, R: tauri::Runtime>(
+    cap: &mut Capture<T>,
+    filter: &str,
+    decoder: &Mutex<PacketDecoder>,
+    mut clock: Option<ReplayClock>,
+    stop: &AtomicBool,
+    manager: &impl Manager<R>,
+) -> Result<(), pcap::Error> {
+    let mut packet_capture = PacketCapture::with_filter(filter);
+    packet_capture.set_linktype(cap.get_datalink());
+
+    while !stop.load(Ordering::Relaxed) {
+        let packet = match cap.next_packet() {
+            Ok(packet) => packet,
+            Err(pcap::Error::TimeoutExpired) => continue,
+            Err(err) => return Err(err),
+        };
+        if let Some(clock) = clock.as_mut() {
+            clock.wait(&packet);
+        }
+
+        let messages = {
+            let mut decoder = decoder.lock().unwrap_or_else(PoisonError::into_inner);
+            packet_capture.process(&packet, &mut decoder);
+            decoder.get_messages()
+        };
+
+        // follow the client to the game server it was handed over to
+        if let Some(filter) = packet_capture.updated_filter() {
+            cap.filter(&filter, true)?;
+        }
+
+        let server_message = ServerMessage::new(messages);
+
+        rs2js(serde_json::to_string(&server_message).unwrap(), manager);
+    }
+
+    Ok(())
+}
+
 fn rs2js<R: tauri::Runtime>(message: String, manager: &impl Manager<R>) {
-    manager.emit_all("rs2js", message).unwrap();
+    if let Err(err) = manager.emit_all("rs2js", message) {
+        warn!("Unable to send messages to the UI: {}", err);
+    }
 }
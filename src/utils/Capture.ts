@@ -0,0 +1,14 @@
+export interface CaptureSettings {
+  device: string | null;
+  filter: string;
+  snaplen: number;
+  promisc: boolean;
+  buffer_size: number | null;
+}
+
+export interface DeviceInfo {
+  name: string;
+  description: string | null;
+  addresses: string[];
+  loopback: boolean;
+}
@@ -1,8 +1,25 @@
+export interface MessageHeader {
+  id: number;
+  length_type: number;
+  length: number;
+  instance_id: number | null;
+  raw: string;
+}
+
+export interface FieldSpan {
+  path: string;
+  offset: number;
+  length: number;
+}
+
 export interface DofusPacket {
   source: string;
   time: string;
+  end_time: string;
   id: string;
   name: string;
+  header: MessageHeader;
   raw: string;
   body: { [key: string]: any };
+  layout: FieldSpan[];
 }